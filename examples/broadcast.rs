@@ -1,163 +1,104 @@
-
 use anyhow::Result;
-use rand::seq::SliceRandom;
-use std::{collections::{HashSet, HashMap}, time::Duration};
-use chaos::{NodeRunner, NodeHandler, data_models::*};
+use std::time::Duration;
+use chaos::{NodeRunner, NodeHandler, context::Context, crdt::GSet, data_models::*, gossip::Gossip, reliable::Reliable, topology::Topology};
+use tracing::{debug, info};
 
-use tokio;
 
-const GOSSIP_READ: &str = "";
-const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(500);
 
 #[tokio::main]
 pub async fn main() -> Result<()>{
     let mut node = NodeRunner::new();
     
-    eprintln!("broadcasting...");
-    
-    let mut handler = BroadcastNode::default();
+    info!("broadcasting...");
     
-    node.register_handler(&mut handler, &[ NodeType::Broadcast ]);
-    node.register_interval(GOSSIP_READ.to_string(), GOSSIP_INTERVAL);
+    let broadcast = BroadcastNode::default();
+    let gossip_tag = broadcast.gossip.tag().to_string();
+    let mut node_types = vec![ NodeType::Broadcast ];
+    node_types.extend(broadcast.gossip.node_types());
+
+    node.register_handler(broadcast, &node_types);
+    node.register_interval(gossip_tag, GOSSIP_INTERVAL);
 
     node.run_node().await?;
 
-    eprintln!("completed broadcasting");
+    info!("completed broadcasting");
     
     Ok(())
 }
 
-const MIN_WIDOWING_SIZE:usize = 5;
-
 #[derive(Debug, Default)]
 struct BroadcastNode {
-    node_id: NodeId,
     neighbors: Vec<NodeId>,
 
-    neighbors_known_msgs: HashMap<NodeId, HashSet<usize>>,
+    // every message we know about, and what every other node is believed to know.
+    // (periodically sends a few of them anything they aren't known to have)
+    gossip: Gossip<GSet<usize>>,
 
-    known_msgs: HashSet<usize>,
+    // re-sends broadcasts to our neighbors until they're acknowledged
+    reliable: Reliable,
 }
 
-impl BroadcastNode {
-    fn update_neighbors(&mut self, node_ids: Vec<NodeId>) {
-        self.neighbors = node_ids;
-    }
+/// a spanning tree of clusters of ~sqrt(n) nodes: each cluster's members hang off its leader, and the
+/// other leaders (and the first cluster's members) off the first node.
+///
+/// Being a tree, a broadcast costs n - 1 forwards plus n - 1 acks (48 messages at 25 nodes, before
+/// any gossip), and reaches every node within 4 hops, with no node forwarding to more than ~2 * sqrt(n).
+fn layout(node_ids: &[NodeId]) -> Topology {
+    let cluster_size = (node_ids.len() as f64).sqrt().ceil() as usize;
+    let Some(root) = node_ids.first() else { return Topology::default() };
+    Topology::hierarchical(node_ids, cluster_size).spanning_tree(root)
 }
 
 impl NodeHandler for BroadcastNode {
     fn init(&mut self, node_id: NodeId, node_ids:Vec<NodeId>) {
-        self.node_id = node_id;
-        self.update_neighbors(node_ids);
+        let layout = layout(&node_ids);
+        debug!("using a layout with diameter {:?}, fanout {} and {} links", layout.diameter(), layout.fanout(), layout.edge_count());
+
+        self.neighbors = layout.neighbors(&node_id);
     }
 
-    fn handle_msg(&mut self, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
-        
-        // ensure we always have a value for `msg.src` here
-        // so we can indiscriminantly unwrap the .get(&msg.src) Option
-        if !self.neighbors_known_msgs.contains_key(&msg.src) { 
-            self.neighbors_known_msgs.insert(msg.src.clone(), HashSet::new());
-        }
+    fn handle_msg(&mut self, ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+        if self.gossip.handle_msg(ctx, &msg) { return None; }
 
-        match msg.body { 
-        Body::Topology { msg_id, topology } => {
-            if let Some(our_neighbors) = topology.get(&self.node_id) {
-                self.update_neighbors(our_neighbors.clone());
-            }
-            
-            Some(vec![NodeMessage {
-                src: self.node_id.clone(),
-                dest: msg.src,
-                body: Body::TopologyOk { 
-                    msg_id: 0, 
-                    in_reply_to: msg_id 
-                },
-            }])
+        match &msg.body { 
+        Body::Topology { .. } => {
+            // (we use our own layout, rather than the suggested one; gossip still reaches every node)
+            Some(vec![msg.reply(Body::TopologyOk)])
         },
-        Body::Broadcast { msg_id, message } => { 
+        Body::Broadcast { message } => { 
+            let known = self.gossip.state().contains(message);
+            let message = GSet::from_iter([*message]);
 
             // first, create the 'ok' response:
-            let mut messages = vec![ 
-                NodeMessage {
-                    src: self.node_id.clone(), 
-                    dest: msg.src.clone(),
-                    body: Body::BroadcastOk { 
-                        msg_id: 0, 
-                        in_reply_to: msg_id 
-                    }, 
-                },
+            let messages = vec![ 
+                msg.reply(Body::BroadcastOk),
             ];
 
-            if self.known_msgs.contains(&message) {
+            // whoever sent it to us already knows it (no harm done if that's a client)
+            self.gossip.mark_known(&msg.src, &message);
+
+            if known {
                 return Some(messages);
             }
+            self.gossip.merge(&message);
 
-            // then all the broadcast messages:
-            messages.extend(
-                self.neighbors.iter()
-                .filter_map(|dest| {
-                    if msg.src.eq(dest) { return None; }
-                    Some(NodeMessage {
-                        src: self.node_id.clone(),
-                        dest: dest.clone(),
-                        body: Body::Broadcast {
-                            msg_id: 0, 
-                            message: message.clone(),
-                        },
-                    })
-                })
-            );
-
-            // now add this to the 'known' messages for the src node.
-            let src_known = self.neighbors_known_msgs.get_mut(&msg.src).unwrap();
-            src_known.insert(message);
-            
-            // finally, add this to our 'known' messages
-            self.known_msgs.insert(message);
+            // then pass it on to our neighbors, until each of them acknowledges it:
+            for dest in self.neighbors.iter().filter(|dest| **dest != msg.src && *dest != ctx.node_id()) {
+                self.reliable.send(ctx, dest, msg.body.clone());
+            }
 
             Some(messages) 
         },
-        Body::Read { msg_id } => {
-
-            // start by getting all the values we know the src node doesn't know 
-            // let src_known = self.neighbors_known_msgs.get(&msg.src).unwrap();
-            // let mut src_unknown: HashSet<_> = self.known_msgs.difference(src_known).copied().collect();
-
-
-            // Now extend that list with an extra set of values, 
-            // randomly selected from the list of all our known values. 
-            // let list:Vec<_> = self.known_msgs.iter().copied().collect();
-            // let mut window_size = MIN_WIDOWING_SIZE.max(list.len()/5);
-            // window_size = window_size.min(list.len());
-
-            // let mut rng = rand::thread_rng();
-            // let extras = list.choose_multiple(&mut rng, window_size).cloned();
-
-            // src_unknown.extend(extras);
-            
-            // `src_unknown` is unchanged at this point, and `extras` is not exhausted...???
-
-            // Finally, construct the response
-            Some(vec![NodeMessage {
-                src: self.node_id.clone(),
-                dest: msg.src,
-                body: Body::ReadOk { 
-                    msg_id: 0, 
-                    in_reply_to: msg_id, 
-                    messages: self.known_msgs.clone(), 
-                },
-            }])
+        Body::Read { key: _ } => {
+            Some(vec![msg.reply(Body::ReadOk { 
+                messages: Some(self.gossip.state().as_set().clone()), 
+                value: None,
+            })])
         },
 
-        Body::ReadOk { msg_id: _, in_reply_to: _, messages } => {
-            
-            // keep track of what our peers know.
-            let src_known = self.neighbors_known_msgs.get_mut(&msg.src).unwrap();
-            src_known.extend(messages.clone());
-
-            // and add this to what we know.
-            self.known_msgs.extend(messages);
-
+        Body::BroadcastOk => {
+            self.reliable.ack(&msg);
             None
         },
 
@@ -166,39 +107,47 @@ impl NodeHandler for BroadcastNode {
         }
     }
 
-    fn handle_interval(&mut self, tag: String, _elapsed: std::time::Duration) -> Option<Vec<NodeMessage>> {
+    fn handle_interval(&mut self, ctx: &Context, tag: String, _elapsed: std::time::Duration) -> Option<Vec<NodeMessage>> {
+        if self.reliable.handle_interval(ctx, &tag) { return None; }
+        if self.gossip.handle_interval(ctx, &tag) { return None; }
 
-        eprintln!("interval fired for tag: {}", tag);
+        debug!("interval fired for tag: {}", tag);
         None
     }
+
+    fn on_shutdown(&mut self, _ctx: &Context) {
+        info!("shutting down, knew about {} messages", self.gossip.state().len());
+        info!("still waiting on acks from peers: {:?}", self.reliable.outstanding());
+    }
 }
 
 
 #[cfg(test)]
 mod broadcast_tests {
     use super::*;
+    use chaos::{check, sim::Cluster};
 
     #[test]
     fn sends_broadcast() {
         let mut node = BroadcastNode::default();
 
-        node.node_id = "n1".to_string();
         node.neighbors.push("c1".to_string());
         node.neighbors.push("c2".to_string());
 
         let msgs = node.handle_msg(
-            NodeMessage {
-                src: "c1".to_string(), 
-                dest: "n1".to_string(), 
-                body: Body::Broadcast { msg_id: 0, message: 1 },
-            }
+            &Context::detached("n1", vec!["n1".to_string()]),
+            NodeMessage::new(
+                "c1".to_string(), 
+                "n1".to_string(), 
+                Body::Broadcast { message: 1 },
+            )
         );
 
         match msgs {
             Some(msgs) => {
                 match &msgs[0].body {
-                    Body::BroadcastOk { msg_id:_, in_reply_to } => {
-                        assert!(*in_reply_to == 0)
+                    Body::BroadcastOk => {
+                        assert!(msgs[0].dest == "c1")
                     },
                     _ => assert!(false, "'broadcast' did not produce a 'broadcast_ok' message"),
                 }
@@ -210,33 +159,41 @@ mod broadcast_tests {
             },
             None => assert!(false, "no response from 'Broadcast'!"),
         }
+
+        // (c1 sent it to us, so only c2 needs it passed on)
+        assert_eq!(node.reliable.outstanding_to("c1"), 0);
+        assert_eq!(node.reliable.outstanding_to("c2"), 1);
     }
 
     #[test]
-    fn read_message_adds_extras() {
+    fn read_returns_gossiped_messages() {
         let mut node = BroadcastNode::default();
-        let mut known_set = HashSet::new();
-        known_set.extend(0..100);
+        let ctx = Context::detached("n1", vec!["n1".to_string(), "n2".to_string()]);
 
-        node.node_id = "n1".to_string();
-        node.neighbors.push("c1".to_string());
-        node.known_msgs.extend(0..100);
-        node.neighbors_known_msgs.insert("c1".to_string(), known_set);
+        let mut gossip = NodeMessage::new(
+            "n2".to_string(), 
+            "n1".to_string(), 
+            Body::custom(&serde_json::json!({"type": "gossip", "state": (0..100).collect::<Vec<usize>>()})).unwrap(),
+        );
+        gossip.header.msg_id = Some(1);
+        assert!(node.handle_msg(&ctx, gossip).is_none());
+        assert_eq!(node.gossip.known_by("n2").map(|known| known.len()), Some(100));
 
         let msg = node.handle_msg(
-            NodeMessage {
-                src: "c1".to_string(), 
-                dest: "n1".to_string(), 
-                body: Body::Read { msg_id: 0 },
-            }
+            &ctx,
+            NodeMessage::new(
+                "c1".to_string(), 
+                "n1".to_string(), 
+                Body::Read { key: None },
+            )
         );
 
         match msg {
             Some(msg) => {
                 assert!(msg.len() == 1);
                 match &msg[0].body {
-                    Body::ReadOk { msg_id:_, in_reply_to:_, messages } => {
-                        assert!(messages.len() == 20)
+                    Body::ReadOk { messages: Some(messages), value: _ } => {
+                        assert!(messages.len() == 100)
                     },
                     _ => assert!(false, "'read' did not produce a 'read_ok' message"),
                 }
@@ -245,4 +202,86 @@ mod broadcast_tests {
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn converges_in_simulated_cluster() {
+        let mut cluster = Cluster::new(5, 42, |_| Box::new(BroadcastNode::default()))
+            .latency(Duration::from_millis(1), Duration::from_millis(100));
+
+        for (i, node_id) in cluster.node_ids().to_vec().iter().enumerate() {
+            cluster.client_request("c1", node_id, Body::Broadcast { message: i });
+        }
+        assert!(cluster.run_until_quiet(Duration::from_secs(10)));
+
+        for node_id in cluster.node_ids().to_vec() {
+            let read_id = cluster.client_request("c1", &node_id, Body::Read { key: None });
+            cluster.run_until_quiet(Duration::from_secs(1));
+
+            match &cluster.reply_to("c1", read_id).unwrap().body {
+                Body::ReadOk { messages: Some(messages), value: _ } => assert_eq!(messages.len(), 5),
+                _ => assert!(false, "'read' did not produce a 'read_ok' message"),
+            }
+        }
+
+        check::broadcast(cluster.history()).unwrap();
+    }
+
+    #[test]
+    fn converges_despite_message_loss() {
+        let mut cluster = Cluster::new(5, 42, |_| Box::new(BroadcastNode::default()))
+            .latency(Duration::from_millis(1), Duration::from_millis(100))
+            .loss(0.3);
+
+        for (i, node_id) in cluster.node_ids().to_vec().iter().enumerate() {
+            cluster.client_request("c1", node_id, Body::Broadcast { message: i });
+        }
+        // (retries are on timers, so the network can go quiet before everything has been delivered)
+        cluster.run_for(Duration::from_secs(30));
+        assert!(cluster.stats().lost > 0);
+
+        for node_id in cluster.node_ids().to_vec() {
+            let read_id = cluster.client_request("c1", &node_id, Body::Read { key: None });
+            cluster.run_for(Duration::from_secs(1));
+
+            match &cluster.reply_to("c1", read_id).unwrap().body {
+                Body::ReadOk { messages: Some(messages), value: _ } => assert_eq!(messages.len(), 5),
+                _ => assert!(false, "'read' did not produce a 'read_ok' message"),
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_replays_same_run() {
+        let run = |seed| {
+            let mut cluster = Cluster::new(5, seed, |_| Box::new(BroadcastNode::default()))
+                .latency(Duration::from_millis(1), Duration::from_millis(100))
+                .loss(0.3);
+            cluster.register_interval("gossip".to_string(), GOSSIP_INTERVAL);
+
+            for (i, node_id) in cluster.node_ids().to_vec().iter().enumerate() {
+                cluster.client_request("c1", node_id, Body::Broadcast { message: i });
+            }
+            cluster.run_for(Duration::from_secs(5));
+            (cluster.stats(), serde_json::to_string(cluster.client_messages()).unwrap())
+        };
+
+        assert_eq!(run(42), run(42));
+    }
+
+    #[test]
+    fn forwards_each_broadcast_once_per_tree_link() {
+        let tree = layout(&(0..25).map(|i| format!("n{}", i)).collect::<Vec<_>>());
+        assert_eq!((tree.edge_count(), tree.diameter(), tree.fanout()), (24, Some(4), 8));
+
+        let mut cluster = Cluster::new(25, 42, |_| Box::new(BroadcastNode::default()))
+            .latency(Duration::from_millis(100), Duration::from_millis(100));
+
+        for message in 0..10 {
+            cluster.client_request("c1", &format!("n{}", message * 2), Body::Broadcast { message });
+        }
+        assert!(cluster.run_until_quiet(Duration::from_secs(10)));
+
+        // (24 forwards and 24 acks each, with gossip left off)
+        assert_eq!(cluster.stats().sent, 10 * 48);
+        check::broadcast(cluster.history()).unwrap();
+    }
+}
@@ -1,39 +1,29 @@
 use anyhow::Result;
-use chaos::{NodeRunner, NodeHandler, data_models::*};
+use chaos::{NodeRunner, NodeHandler, context::Context, data_models::*};
+use tracing::info;
 
 #[tokio::main]
 pub async fn main() -> Result<()>{
     let mut node = NodeRunner::new();
     
-    eprintln!("echoing...");
+    info!("echoing...");
 
-    let mut handler = EchoNode::default();
-    node.register_handler(&mut handler, &[ NodeType::Echo ]);
+    node.register_handler(EchoNode, &[ NodeType::Echo ]);
     node.run_node().await?;
 
-    eprintln!("completed echo");
+    info!("completed echo");
 
     Ok(())
 }
 
 #[derive(Debug, Default)]
-struct EchoNode {
-    node_id: NodeId,
-}
+struct EchoNode;
 
 impl NodeHandler for EchoNode {
-    fn init(&mut self, node_id: NodeId, _node_ids:Vec<NodeId>) {
-        self.node_id = node_id;
-    }
-
-    fn handle_msg(&mut self, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
-        if let Body::Echo { msg_id, echo } = msg.body {
+    fn handle_msg(&mut self, _ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+        if let Body::Echo { echo } = &msg.body {
             Some(vec![
-                NodeMessage {
-                    src: self.node_id.clone(),
-                    dest: msg.src,
-                    body: Body::EchoOk { msg_id: 0, in_reply_to: msg_id, echo: echo }
-                },
+                msg.reply(Body::EchoOk { echo: echo.clone() }),
             ])
         } else {
             None
@@ -0,0 +1,64 @@
+use anyhow::Result;
+use chaos::{NodeRunner, AsyncNodeHandler, context::Context, data_models::*, services::{KvClient, KvError}};
+use tracing::info;
+
+const COUNTER_KEY: &str = "g-counter";
+
+#[tokio::main]
+pub async fn main() -> Result<()>{
+    let mut node = NodeRunner::new();
+
+    info!("counting...");
+
+    node.register_async_handler(CounterNode::new(KvClient::seq_kv(&node)), &[ NodeType::Counter ]);
+    node.run_node().await?;
+
+    info!("completed counting");
+
+    Ok(())
+}
+
+/// Keeps a single shared counter in the seq-kv service.
+///
+/// Every request is handled in its own task, and replied to once the service has answered.
+struct CounterNode {
+    kv: KvClient,
+}
+
+impl CounterNode {
+    fn new(kv: KvClient) -> Self {
+        Self { kv }
+    }
+}
+
+impl AsyncNodeHandler for CounterNode {
+    async fn handle_msg(&self, _ctx: Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+        let body = match msg.body {
+            Body::Add { delta } => match add(&self.kv, delta).await {
+                Ok(_) => Body::AddOk,
+                Err(err) => err.into(),
+            },
+            Body::Read { key: _ } => match read(&self.kv).await {
+                Ok(value) => Body::ReadOk { messages: None, value: Some(value.into()) },
+                Err(err) => err.into(),
+            },
+
+            // and we don't handle any other messages
+            _ => return None,
+        };
+        Some(vec![msg.reply(body)])
+    }
+}
+
+/// adds `delta` to the counter, retrying whenever another node updated it first.
+async fn add(kv: &KvClient, delta: i64) -> Result<(), KvError> {
+    kv.update(COUNTER_KEY, 0, |current| Some(current + delta)).await?;
+    Ok(())
+}
+
+/// reads the counter, then confirms the value is still current with a no-op `cas`.
+///
+/// (a plain seq-kv read is allowed to return a stale value)
+async fn read(kv: &KvClient) -> Result<i64, KvError> {
+    kv.update(COUNTER_KEY, 0, |current| Some(*current)).await
+}
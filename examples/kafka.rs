@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chaos::{NodeRunner, AsyncNodeHandler, context::Context, data_models::*, services::{KvClient, KvError}};
+use tracing::info;
+
+#[tokio::main]
+pub async fn main() -> Result<()>{
+    let mut node = NodeRunner::new();
+
+    info!("logging...");
+
+    node.register_async_handler(KafkaNode::new(KvClient::lin_kv(&node)), &[ NodeType::Kafka ]);
+    node.run_node().await?;
+
+    info!("completed logging");
+
+    Ok(())
+}
+
+/// Keeps each key's log, and its committed offset, in the lin-kv service.
+///
+/// A log is stored as a single array of messages, so a message's offset is its index,
+/// and appending with `cas` both allocates the offset and writes the message.
+struct KafkaNode {
+    kv: KvClient,
+}
+
+impl KafkaNode {
+    fn new(kv: KvClient) -> Self {
+        Self { kv }
+    }
+}
+
+impl AsyncNodeHandler for KafkaNode {
+    async fn handle_msg(&self, _ctx: Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+        let kv = &self.kv;
+        let body = match msg.body.clone() {
+            Body::Send { key, msg } => match append(kv, &key, msg).await {
+                Ok(offset) => Body::SendOk { offset },
+                Err(err) => err.into(),
+            },
+            Body::Poll { offsets } => match poll(kv, offsets).await {
+                Ok(msgs) => Body::PollOk { msgs },
+                Err(err) => err.into(),
+            },
+            Body::CommitOffsets { offsets } => match commit(kv, offsets).await {
+                Ok(_) => Body::CommitOffsetsOk,
+                Err(err) => err.into(),
+            },
+            Body::ListCommittedOffsets { keys } => match list_committed(kv, keys).await {
+                Ok(offsets) => Body::ListCommittedOffsetsOk { offsets },
+                Err(err) => err.into(),
+            },
+
+            // and we don't handle any other messages
+            _ => return None,
+        };
+        Some(vec![msg.reply(body)])
+    }
+}
+
+fn log_key(key: &str) -> String { format!("log-{}", key) }
+fn commit_key(key: &str) -> String { format!("commit-{}", key) }
+
+/// appends `msg` to the log for `key`, and returns the offset it was written at.
+async fn append(kv: &KvClient, key: &str, msg: i64) -> Result<usize, KvError> {
+    let log = kv.update(log_key(key), Vec::new(), |log| {
+        let mut updated = log.clone();
+        updated.push(msg);
+        Some(updated)
+    }).await?;
+    Ok(log.len() - 1)
+}
+
+/// returns every message at or after the requested offset, for each requested key.
+async fn poll(kv: &KvClient, offsets: HashMap<String, usize>) -> Result<HashMap<String, Vec<(usize, i64)>>, KvError> {
+    let mut msgs = HashMap::new();
+    for (key, from) in offsets {
+        let log = read_log(kv, &key).await?;
+        let entries = log.into_iter()
+            .enumerate()
+            .skip(from)
+            .collect();
+        msgs.insert(key, entries);
+    }
+    Ok(msgs)
+}
+
+/// moves each key's committed offset forward (committed offsets never move backwards).
+async fn commit(kv: &KvClient, offsets: HashMap<String, usize>) -> Result<(), KvError> {
+    for (key, offset) in offsets {
+        // (`None` is only ever a missing key, so even a commit of offset 0 gets written)
+        kv.update(commit_key(&key), None, |current: &Option<usize>| match current {
+            Some(current) if *current >= offset => None,
+            _ => Some(Some(offset)),
+        }).await?;
+    }
+    Ok(())
+}
+
+async fn list_committed(kv: &KvClient, keys: Vec<String>) -> Result<HashMap<String, usize>, KvError> {
+    let mut offsets = HashMap::new();
+    for key in keys {
+        if let Some(offset) = read_committed(kv, &key).await? {
+            offsets.insert(key, offset);
+        }
+    }
+    Ok(offsets)
+}
+
+async fn read_log(kv: &KvClient, key: &str) -> Result<Vec<i64>, KvError> {
+    match kv.read(log_key(key)).await {
+        Err(KvError::KeyDoesNotExist) => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+async fn read_committed(kv: &KvClient, key: &str) -> Result<Option<usize>, KvError> {
+    match kv.read(commit_key(key)).await {
+        Ok(offset) => Ok(Some(offset)),
+        Err(KvError::KeyDoesNotExist) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
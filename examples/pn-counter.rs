@@ -0,0 +1,132 @@
+use anyhow::Result;
+use std::time::Duration;
+use chaos::{NodeRunner, NodeHandler, context::Context, crdt::PNCounter, data_models::*, gossip::Gossip};
+use tracing::{debug, info};
+
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(250);
+
+#[tokio::main]
+pub async fn main() -> Result<()>{
+    let mut node = NodeRunner::new();
+
+    info!("counting...");
+
+    let counter = PnCounterNode::default();
+    let gossip_tag = counter.gossip.tag().to_string();
+    let mut node_types = vec![ NodeType::PnCounter ];
+    node_types.extend(counter.gossip.node_types());
+
+    node.register_handler(counter, &node_types);
+    node.register_interval(gossip_tag, GOSSIP_INTERVAL);
+
+    node.run_node().await?;
+
+    info!("completed counting");
+
+    Ok(())
+}
+
+/// Keeps its own replica of the counter, and gossips it with every other node.
+///
+/// Adds are applied (and acknowledged) locally, so the counter stays available through a
+/// partition, and the replicas converge once it heals.
+#[derive(Debug, Default)]
+struct PnCounterNode {
+    gossip: Gossip<PNCounter>,
+}
+
+impl NodeHandler for PnCounterNode {
+    fn handle_msg(&mut self, ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+        if self.gossip.handle_msg(ctx, &msg) { return None; }
+
+        match &msg.body {
+        Body::Add { delta } => {
+            self.gossip.update(|counter| counter.add(ctx.node_id(), *delta));
+            Some(vec![msg.reply(Body::AddOk)])
+        },
+        Body::Read { key: None } => {
+            Some(vec![msg.reply(Body::ReadOk {
+                messages: None,
+                value: Some(self.gossip.state().value().into()),
+            })])
+        },
+
+        // and we don't handle any other messages
+        _ => None,
+        }
+    }
+
+    fn handle_interval(&mut self, ctx: &Context, tag: String, _elapsed: Duration) -> Option<Vec<NodeMessage>> {
+        if self.gossip.handle_interval(ctx, &tag) { return None; }
+
+        debug!("interval fired for tag: {}", tag);
+        None
+    }
+
+    fn on_shutdown(&mut self, _ctx: &Context) {
+        info!("shutting down, counter is at {}", self.gossip.state().value());
+    }
+}
+
+
+#[cfg(test)]
+mod pn_counter_tests {
+    use super::*;
+    use chaos::{check, sim::Cluster};
+
+    fn read_value(cluster: &mut Cluster, node_id: &str) -> i64 {
+        let read_id = cluster.client_request("c1", node_id, Body::Read { key: None });
+        cluster.run_for(Duration::from_millis(500));
+
+        match &cluster.reply_to("c1", read_id).unwrap().body {
+            Body::ReadOk { value: Some(value), .. } => value.as_i64().unwrap(),
+            _ => panic!("'read' did not produce a 'read_ok' with a value"),
+        }
+    }
+
+    #[test]
+    fn adds_negative_deltas_locally() {
+        let mut node = PnCounterNode::default();
+        let ctx = Context::detached("n1", vec!["n1".to_string(), "n2".to_string()]);
+
+        for delta in [5, -8, 1] {
+            let replies = node.handle_msg(&ctx, NodeMessage::new("c1".to_string(), "n1".to_string(), Body::Add { delta }));
+            assert!(matches!(replies.as_deref(), Some([NodeMessage { body: Body::AddOk, .. }])));
+        }
+        assert_eq!(node.gossip.state().value(), -2);
+    }
+
+    #[test]
+    fn converges_after_a_partition_heals() {
+        let mut seed = 42;
+        let mut cluster = Cluster::new(3, seed, |_| {
+                seed += 1;
+                Box::new(PnCounterNode { gossip: Gossip::default().with_seed(seed) })
+            })
+            .latency(Duration::from_millis(1), Duration::from_millis(50))
+            .loss(0.2);
+        cluster.register_interval("gossip".to_string(), GOSSIP_INTERVAL);
+        cluster.partition_at(Duration::ZERO, &[&["n0"], &["n1", "n2"]]);
+        cluster.heal_at(Duration::from_secs(5));
+
+        let mut expected = 0;
+        for (i, node_id) in cluster.node_ids().to_vec().iter().cycle().take(12).enumerate() {
+            let delta = if i % 3 == 0 { -(i as i64) } else { i as i64 };
+            expected += delta;
+            cluster.client_request("c1", node_id, Body::Add { delta });
+        }
+        cluster.run_for(Duration::from_secs(1));
+
+        // (the isolated node only knows its own adds)
+        assert!(cluster.is_partitioned("n0", "n1"));
+        assert_ne!(read_value(&mut cluster, "n0"), expected);
+
+        cluster.run_for(Duration::from_secs(10));
+        for node_id in cluster.node_ids().to_vec() {
+            assert_eq!(read_value(&mut cluster, &node_id), expected, "{} hasn't converged", node_id);
+        }
+
+        check::pn_counter(cluster.history()).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chaos::{NodeRunner, NodeHandler, context::Context, data_models::*};
+use tracing::info;
+
+#[tokio::main]
+pub async fn main() -> Result<()>{
+    let mut node = NodeRunner::new();
+
+    info!("transacting...");
+
+    node.register_handler(TxnNode::default(), &[ NodeType::Txn ]);
+    node.run_node().await?;
+
+    info!("completed transacting");
+
+    Ok(())
+}
+
+/// A totally-available key/value store, replicated to every peer.
+///
+/// Each txn is applied in a single `handle_msg()` call, so no other txn can observe
+/// its intermediate state, and only its final writes are replicated to peers.
+/// That gives read-committed isolation (which also covers read-uncommitted).
+#[derive(Debug, Default)]
+struct TxnNode {
+    store: HashMap<usize, i64>,
+}
+
+impl TxnNode {
+    /// applies every op in order, filling in the value for each read.
+    fn apply(&mut self, txn: Vec<MicroOp>) -> Vec<MicroOp> {
+        txn.into_iter()
+            .map(|op| match op {
+                MicroOp::Read { key, value: _ } =>
+                    MicroOp::Read { key, value: self.store.get(&key).copied() },
+                MicroOp::Write { key, value } => {
+                    self.store.insert(key, value);
+                    op
+                },
+            })
+            .collect()
+    }
+
+    /// the last write to each key, which is all a peer needs to converge.
+    fn committed_writes(txn: &[MicroOp]) -> Vec<MicroOp> {
+        let mut writes: HashMap<usize, i64> = HashMap::new();
+        txn.iter().for_each(|op| {
+            if let MicroOp::Write { key, value } = op {
+                writes.insert(*key, *value);
+            }
+        });
+
+        writes.into_iter()
+            .map(|(key, value)| MicroOp::Write { key, value })
+            .collect()
+    }
+}
+
+impl NodeHandler for TxnNode {
+    fn handle_msg(&mut self, ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+        let Body::Txn { txn } = &msg.body else { return None };
+
+        let results = self.apply(txn.clone());
+
+        // writes replicated from a peer are applied, but not acknowledged or passed along.
+        if ctx.node_ids().contains(&msg.src) {
+            return None;
+        }
+
+        let writes = TxnNode::committed_writes(&results);
+
+        if !writes.is_empty() {
+            ctx.node_ids().iter()
+                .filter(|peer| *peer != ctx.node_id())
+                .for_each(|peer| { ctx.send(peer, Body::Txn { txn: writes.clone() }); });
+        }
+
+        Some(vec![msg.reply(Body::TxnOk { txn: results })])
+    }
+}
@@ -0,0 +1,469 @@
+use std::{collections::{HashMap, HashSet, hash_map::Entry}, fmt::Display, time::Duration};
+
+use crate::data_models::*;
+
+/// A single client operation: the request a client sent, and the reply it got back (if any).
+#[derive(Debug, Clone)]
+pub struct Op {
+    pub client: NodeId,
+    pub node: NodeId,
+    pub msg_id: MsgId,
+    pub invoked_at: Duration,
+    pub request: Body,
+    pub completed_at: Option<Duration>,
+    pub reply: Option<Body>,
+}
+
+impl Op {
+    /// `true` if the operation completed with anything but an `error`.
+    pub fn is_ok(&self) -> bool {
+        matches!(&self.reply, Some(reply) if !matches!(reply, Body::Error { .. }))
+    }
+
+    /// `true` if the operation definitely did not take effect.
+    pub fn is_failed(&self) -> bool {
+        matches!(&self.reply, Some(Body::Error { code, .. }) if code.is_definite())
+    }
+
+    /// `true` if this operation completed before `other` was invoked.
+    fn precedes(&self, other: &Op) -> bool {
+        self.completed_at.is_some_and(|at| at <= other.invoked_at)
+    }
+}
+
+/// The client operations of a run, in the order they were invoked.
+///
+/// `sim::Cluster` records one automatically (see `Cluster::history()`), but a history can
+/// also be built by hand, ie when replaying a log of messages.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    ops: Vec<Op>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// records a request sent by a client.  Requests without a `msg_id` can't be matched to a reply, so are ignored.
+    pub fn invoke(&mut self, at: Duration, msg: &NodeMessage) {
+        let Some(msg_id) = msg.header.msg_id else { return };
+        self.ops.push(Op {
+            client: msg.src.clone(),
+            node: msg.dest.clone(),
+            msg_id,
+            invoked_at: at,
+            request: msg.body.clone(),
+            completed_at: None,
+            reply: None,
+        });
+    }
+
+    /// records a reply delivered to a client, completing the request it answers.
+    pub fn complete(&mut self, at: Duration, msg: &NodeMessage) {
+        let Some(in_reply_to) = msg.header.in_reply_to else { return };
+        let op = self.ops.iter_mut()
+            .find(|op| op.client == msg.dest && op.msg_id == in_reply_to && op.reply.is_none());
+
+        if let Some(op) = op {
+            op.completed_at = Some(at);
+            op.reply = Some(msg.body.clone());
+        }
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// the last successful operation on each node that `pick` accepts.
+    fn last_ok_per_node(&self, pick: impl Fn(&Op) -> bool) -> HashMap<&NodeId, &Op> {
+        let mut last = HashMap::new();
+        for op in self.ops.iter().filter(|op| op.is_ok() && pick(op)) {
+            last.insert(&op.node, op);
+        }
+        last
+    }
+}
+
+
+/// An invariant that a history broke.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// an `echo_ok` didn't echo back what was sent.
+    EchoMismatch { msg_id: MsgId, sent: String, received: String },
+    /// a `generate_ok` id was handed out more than once.
+    DuplicateId { id: String, count: usize },
+    /// an acknowledged broadcast was missing from a node's final read.
+    LostBroadcast { node: NodeId, message: usize },
+    /// a read returned a message that no client ever broadcast.
+    UnexpectedBroadcast { node: NodeId, message: usize },
+    /// a node's final read of the counter was outside what the adds allow.
+    CounterOutOfBounds { node: NodeId, value: i64, min: i64, max: i64 },
+    /// two acknowledged sends were given the same offset in the same log.
+    DuplicateOffset { key: String, offset: usize },
+    /// a send that happened after another (in real time) was given a lower offset.
+    NonMonotonicOffset { key: String, earlier: usize, later: usize },
+    /// a poll returned a log's messages out of offset order.
+    UnorderedPoll { key: String, offsets: Vec<usize> },
+    /// a poll returned a different message than the one acknowledged at that offset.
+    OffsetMismatch { key: String, offset: usize, sent: i64, polled: i64 },
+    /// a log's committed offset went backwards.
+    CommitRegressed { key: String, from: usize, to: usize },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::EchoMismatch { msg_id, sent, received } =>
+                write!(f, "echo {} sent {:?} but received {:?}", msg_id, sent, received),
+            Violation::DuplicateId { id, count } =>
+                write!(f, "id {:?} was generated {} times", id, count),
+            Violation::LostBroadcast { node, message } =>
+                write!(f, "acknowledged message {} is missing from {}'s final read", message, node),
+            Violation::UnexpectedBroadcast { node, message } =>
+                write!(f, "{} read message {}, which was never broadcast", node, message),
+            Violation::CounterOutOfBounds { node, value, min, max } =>
+                write!(f, "{} read {}, expected a value in {}..={}", node, value, min, max),
+            Violation::DuplicateOffset { key, offset } =>
+                write!(f, "log {:?} acknowledged two sends at offset {}", key, offset),
+            Violation::NonMonotonicOffset { key, earlier, later } =>
+                write!(f, "log {:?} gave offset {} to a send that followed one at offset {}", key, later, earlier),
+            Violation::UnorderedPoll { key, offsets } =>
+                write!(f, "log {:?} was polled out of order: {:?}", key, offsets),
+            Violation::OffsetMismatch { key, offset, sent, polled } =>
+                write!(f, "log {:?} at offset {} was sent {} but polled as {}", key, offset, sent, polled),
+            Violation::CommitRegressed { key, from, to } =>
+                write!(f, "log {:?}'s committed offset went from {} back to {}", key, from, to),
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+fn verdict(violations: Vec<Violation>) -> Result<(), Vec<Violation>> {
+    if violations.is_empty() { Ok(()) } else { Err(violations) }
+}
+
+
+/// every `echo_ok` echoes back exactly what its `echo` sent.
+pub fn echo(history: &History) -> Result<(), Vec<Violation>> {
+    let violations = history.ops.iter()
+        .filter_map(|op| match (&op.request, &op.reply) {
+            (Body::Echo { echo: sent }, Some(Body::EchoOk { echo: received })) if sent != received =>
+                Some(Violation::EchoMismatch { msg_id: op.msg_id, sent: sent.clone(), received: received.clone() }),
+            _ => None,
+        })
+        .collect();
+
+    verdict(violations)
+}
+
+/// every `generate_ok` id is globally unique.
+pub fn unique_ids(history: &History) -> Result<(), Vec<Violation>> {
+    let mut counts: HashMap<&String, usize> = HashMap::new();
+    for op in &history.ops {
+        if let Some(Body::GenerateOk { id }) = &op.reply {
+            *counts.entry(id).or_default() += 1;
+        }
+    }
+
+    let violations = counts.into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(id, count)| Violation::DuplicateId { id: id.clone(), count })
+        .collect();
+
+    verdict(violations)
+}
+
+/// every acknowledged broadcast appears in each node's final read, and reads only return broadcast messages.
+///
+/// A node's final read is its last successful `read`.  Broadcasts acknowledged after that read
+/// was invoked aren't expected in it, and nodes that were never read aren't checked.
+pub fn broadcast(history: &History) -> Result<(), Vec<Violation>> {
+    let sent: HashSet<usize> = history.ops.iter()
+        .filter_map(|op| match op.request {
+            Body::Broadcast { message } => Some(message),
+            _ => None,
+        })
+        .collect();
+    let acked: Vec<(&Op, usize)> = history.ops.iter()
+        .filter(|op| op.is_ok())
+        .filter_map(|op| match op.request {
+            Body::Broadcast { message } => Some((op, message)),
+            _ => None,
+        })
+        .collect();
+
+    let mut violations = Vec::new();
+    let final_reads = history.last_ok_per_node(|op| matches!(op.reply, Some(Body::ReadOk { messages: Some(_), .. })));
+    for (node, read) in final_reads {
+        let Some(Body::ReadOk { messages: Some(messages), .. }) = &read.reply else { continue };
+
+        violations.extend(acked.iter()
+            .filter(|(op, message)| op.precedes(read) && !messages.contains(message))
+            .map(|(_, message)| Violation::LostBroadcast { node: node.clone(), message: *message }));
+        violations.extend(messages.iter()
+            .filter(|message| !sent.contains(message))
+            .map(|message| Violation::UnexpectedBroadcast { node: node.clone(), message: *message }));
+    }
+
+    verdict(violations)
+}
+
+/// each node's final read of the counter is at least every add acknowledged before it,
+/// and at most every add that might have taken effect by then.
+pub fn g_counter(history: &History) -> Result<(), Vec<Violation>> {
+    counter(history)
+}
+
+/// the same as `g_counter()`, but deltas may be negative: an add that might (or might not) have
+/// taken effect by a read can lower its minimum, or raise its maximum, depending on its sign.
+pub fn pn_counter(history: &History) -> Result<(), Vec<Violation>> {
+    counter(history)
+}
+
+fn counter(history: &History) -> Result<(), Vec<Violation>> {
+    let adds: Vec<(&Op, i64)> = history.ops.iter()
+        .filter_map(|op| match op.request {
+            Body::Add { delta } => Some((op, delta)),
+            _ => None,
+        })
+        .collect();
+
+    let mut violations = Vec::new();
+    let final_reads = history.last_ok_per_node(|op| matches!(op.request, Body::Read { .. })
+        && matches!(op.reply, Some(Body::ReadOk { value: Some(_), .. })));
+    for (node, read) in final_reads {
+        let Some(Body::ReadOk { value: Some(value), .. }) = &read.reply else { continue };
+        let value = value.as_i64().unwrap_or(i64::MIN);
+
+        let (mut min, mut max) = (0, 0);
+        for (op, delta) in &adds {
+            if op.is_ok() && op.precedes(read) {
+                min += delta;
+                max += delta;
+            } else if !op.is_failed() && read.completed_at.is_some_and(|at| op.invoked_at <= at) {
+                // (uncertain: it may or may not be counted yet)
+                if *delta < 0 { min += delta } else { max += delta }
+            }
+        }
+
+        if value < min || value > max {
+            violations.push(Violation::CounterOutOfBounds { node: node.clone(), value, min, max });
+        }
+    }
+
+    verdict(violations)
+}
+
+/// each log hands out unique, increasing offsets, polls return them in order and agree with
+/// what was sent, and committed offsets never go backwards.
+pub fn kafka(history: &History) -> Result<(), Vec<Violation>> {
+    let mut violations = Vec::new();
+
+    // acknowledged sends, per log
+    let mut sends: HashMap<&String, Vec<(&Op, usize, i64)>> = HashMap::new();
+    for op in &history.ops {
+        if let (Body::Send { key, msg }, Some(Body::SendOk { offset })) = (&op.request, &op.reply) {
+            sends.entry(key).or_default().push((op, *offset, *msg));
+        }
+    }
+
+    let mut sent_at: HashMap<(&String, usize), i64> = HashMap::new();
+    for (key, sends) in &sends {
+        for (idx, (op, offset, msg)) in sends.iter().enumerate() {
+            match sent_at.entry((key, *offset)) {
+                Entry::Occupied(_) => violations.push(Violation::DuplicateOffset { key: key.to_string(), offset: *offset }),
+                Entry::Vacant(entry) => { entry.insert(*msg); },
+            }
+
+            violations.extend(sends[idx + 1..].iter()
+                .filter_map(|(other, other_offset, _)| {
+                    if op.precedes(other) && other_offset <= offset {
+                        Some((*offset, *other_offset))
+                    } else if other.precedes(op) && offset <= other_offset {
+                        Some((*other_offset, *offset))
+                    } else {
+                        None
+                    }
+                })
+                .map(|(earlier, later)| Violation::NonMonotonicOffset { key: key.to_string(), earlier, later }));
+        }
+    }
+
+    for op in &history.ops {
+        let Some(Body::PollOk { msgs }) = &op.reply else { continue };
+        for (key, entries) in msgs {
+            if entries.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                let offsets = entries.iter().map(|(offset, _)| *offset).collect();
+                violations.push(Violation::UnorderedPoll { key: key.clone(), offsets });
+            }
+
+            violations.extend(entries.iter()
+                .filter_map(|(offset, polled)| match sent_at.get(&(key, *offset)) {
+                    Some(sent) if sent != polled => Some(Violation::OffsetMismatch {
+                        key: key.clone(), offset: *offset, sent: *sent, polled: *polled,
+                    }),
+                    _ => None,
+                }));
+        }
+    }
+
+    // a listing that follows another (in real time) must not see a lower committed offset,
+    // for any key both of them asked about.
+    let listings: Vec<(&Op, &Vec<String>, &HashMap<String, usize>)> = history.ops.iter()
+        .filter_map(|op| match (&op.request, &op.reply) {
+            (Body::ListCommittedOffsets { keys }, Some(Body::ListCommittedOffsetsOk { offsets })) => Some((op, keys, offsets)),
+            _ => None,
+        })
+        .collect();
+    for (earlier, _, earlier_offsets) in &listings {
+        for (_, later_keys, later_offsets) in listings.iter().filter(|(later, _, _)| earlier.precedes(later)) {
+            violations.extend(earlier_offsets.iter()
+                .filter(|(key, _)| later_keys.contains(key))
+                .filter_map(|(key, from)| match later_offsets.get(key) {
+                    Some(to) if to < from => Some(Violation::CommitRegressed { key: key.clone(), from: *from, to: *to }),
+                    None => Some(Violation::CommitRegressed { key: key.clone(), from: *from, to: 0 }),
+                    _ => None,
+                }));
+        }
+    }
+
+    verdict(violations)
+}
+
+
+#[cfg(test)]
+mod check_tests {
+    use super::*;
+
+    /// records a request from `c1` to `node` at `invoked` ms, and its reply at `completed` ms.
+    fn record(history: &mut History, node: &str, msg_id: MsgId, (invoked, completed): (u64, u64), request: Body, reply: Body) {
+        let mut msg = NodeMessage::new("c1".to_string(), node.to_string(), request);
+        msg.header.msg_id = Some(msg_id);
+        history.invoke(Duration::from_millis(invoked), &msg);
+        history.complete(Duration::from_millis(completed), &msg.reply(reply));
+    }
+
+    fn read_ok(messages: &[usize]) -> Body {
+        Body::ReadOk { messages: Some(messages.iter().copied().collect()), value: None }
+    }
+
+    #[test]
+    fn echo_and_unique_ids() {
+        let mut history = History::new();
+        record(&mut history, "n0", 1, (0, 1), Body::Echo { echo: "hi".to_string() }, Body::EchoOk { echo: "hi".to_string() });
+        record(&mut history, "n0", 2, (0, 1), Body::Generate, Body::GenerateOk { id: "a".to_string() });
+        record(&mut history, "n1", 3, (0, 1), Body::Generate, Body::GenerateOk { id: "b".to_string() });
+        assert!(echo(&history).is_ok());
+        assert!(unique_ids(&history).is_ok());
+
+        record(&mut history, "n0", 4, (2, 3), Body::Echo { echo: "hi".to_string() }, Body::EchoOk { echo: "bye".to_string() });
+        record(&mut history, "n1", 5, (2, 3), Body::Generate, Body::GenerateOk { id: "a".to_string() });
+        assert!(matches!(echo(&history).unwrap_err()[..], [Violation::EchoMismatch { msg_id: 4, .. }]));
+        assert_eq!(unique_ids(&history).unwrap_err(), vec![Violation::DuplicateId { id: "a".to_string(), count: 2 }]);
+    }
+
+    #[test]
+    fn broadcast_checks_final_reads() {
+        let mut history = History::new();
+        record(&mut history, "n0", 1, (0, 1), Body::Broadcast { message: 1 }, Body::BroadcastOk);
+        record(&mut history, "n1", 2, (0, 10), Body::Broadcast { message: 2 }, Body::BroadcastOk);
+        // n0's first read is superseded, and message 2 wasn't acknowledged before n1's read
+        record(&mut history, "n0", 3, (2, 3), Body::Read { key: None }, read_ok(&[]));
+        record(&mut history, "n0", 4, (20, 21), Body::Read { key: None }, read_ok(&[1, 2]));
+        record(&mut history, "n1", 5, (5, 6), Body::Read { key: None }, read_ok(&[1]));
+        assert!(broadcast(&history).is_ok());
+
+        record(&mut history, "n1", 6, (20, 21), Body::Read { key: None }, read_ok(&[1, 3]));
+        let violations = broadcast(&history).unwrap_err();
+        assert!(violations.contains(&Violation::LostBroadcast { node: "n1".to_string(), message: 2 }));
+        assert!(violations.contains(&Violation::UnexpectedBroadcast { node: "n1".to_string(), message: 3 }));
+    }
+
+    #[test]
+    fn g_counter_bounds_final_reads() {
+        let mut history = History::new();
+        record(&mut history, "n0", 1, (0, 1), Body::Add { delta: 2 }, Body::AddOk);
+        // may or may not have happened
+        record(&mut history, "n1", 2, (0, 5), Body::Add { delta: 3 }, Body::Error { code: ErrorCode::Timeout, text: String::new() });
+        // definitely didn't happen
+        record(&mut history, "n1", 3, (0, 5), Body::Add { delta: 7 }, Body::Error { code: ErrorCode::Abort, text: String::new() });
+
+        let read_ok = |value: i64| Body::ReadOk { messages: None, value: Some(value.into()) };
+        record(&mut history, "n0", 4, (10, 11), Body::Read { key: None }, read_ok(2));
+        record(&mut history, "n1", 5, (10, 11), Body::Read { key: None }, read_ok(5));
+        assert!(g_counter(&history).is_ok());
+
+        record(&mut history, "n1", 6, (12, 13), Body::Read { key: None }, read_ok(12));
+        assert_eq!(g_counter(&history).unwrap_err(), vec![
+            Violation::CounterOutOfBounds { node: "n1".to_string(), value: 12, min: 2, max: 5 },
+        ]);
+    }
+
+    #[test]
+    fn pn_counter_bounds_with_negative_deltas() {
+        let mut history = History::new();
+        record(&mut history, "n0", 1, (0, 1), Body::Add { delta: 5 }, Body::AddOk);
+        record(&mut history, "n0", 2, (2, 3), Body::Add { delta: -3 }, Body::AddOk);
+        // may or may not have happened
+        record(&mut history, "n1", 3, (0, 5), Body::Add { delta: -4 }, Body::Error { code: ErrorCode::Timeout, text: String::new() });
+        record(&mut history, "n1", 4, (0, 5), Body::Add { delta: 1 }, Body::Error { code: ErrorCode::Timeout, text: String::new() });
+
+        let read_ok = |value: i64| Body::ReadOk { messages: None, value: Some(value.into()) };
+        record(&mut history, "n0", 5, (10, 11), Body::Read { key: None }, read_ok(-2));
+        record(&mut history, "n1", 6, (10, 11), Body::Read { key: None }, read_ok(3));
+        assert!(pn_counter(&history).is_ok());
+
+        record(&mut history, "n1", 7, (12, 13), Body::Read { key: None }, read_ok(-3));
+        assert_eq!(pn_counter(&history).unwrap_err(), vec![
+            Violation::CounterOutOfBounds { node: "n1".to_string(), value: -3, min: -2, max: 3 },
+        ]);
+    }
+
+    #[test]
+    fn kafka_checks_offsets() {
+        let mut history = History::new();
+        let send = |msg| Body::Send { key: "k".to_string(), msg };
+        let poll_ok = |entries: Vec<(usize, i64)>| Body::PollOk { msgs: HashMap::from([("k".to_string(), entries)]) };
+        let listed = |offset| Body::ListCommittedOffsetsOk { offsets: HashMap::from([("k".to_string(), offset)]) };
+        let list = || Body::ListCommittedOffsets { keys: vec!["k".to_string()] };
+
+        record(&mut history, "n0", 1, (0, 1), send(10), Body::SendOk { offset: 0 });
+        record(&mut history, "n1", 2, (2, 3), send(11), Body::SendOk { offset: 1 });
+        record(&mut history, "n0", 3, (4, 5), Body::Poll { offsets: HashMap::from([("k".to_string(), 0)]) }, poll_ok(vec![(0, 10), (1, 11)]));
+        record(&mut history, "n0", 4, (6, 7), list(), listed(1));
+        assert!(kafka(&history).is_ok());
+
+        record(&mut history, "n1", 5, (8, 9), send(12), Body::SendOk { offset: 1 });
+        record(&mut history, "n0", 6, (8, 9), Body::Poll { offsets: HashMap::from([("k".to_string(), 0)]) }, poll_ok(vec![(1, 13), (0, 10)]));
+        record(&mut history, "n1", 7, (8, 9), list(), listed(0));
+        let violations = kafka(&history).unwrap_err();
+        assert!(violations.contains(&Violation::DuplicateOffset { key: "k".to_string(), offset: 1 }));
+        assert!(violations.contains(&Violation::NonMonotonicOffset { key: "k".to_string(), earlier: 1, later: 1 }));
+        assert!(violations.contains(&Violation::UnorderedPoll { key: "k".to_string(), offsets: vec![1, 0] }));
+        assert!(violations.contains(&Violation::CommitRegressed { key: "k".to_string(), from: 1, to: 0 }));
+        assert!(violations.iter().any(|v| matches!(v, Violation::OffsetMismatch { offset: 1, polled: 13, .. })));
+    }
+
+    #[test]
+    fn kafka_only_compares_listed_keys() {
+        let mut history = History::new();
+        let list = |keys: &[&str]| Body::ListCommittedOffsets { keys: keys.iter().map(|key| key.to_string()).collect() };
+        let listed = |offsets: &[(&str, usize)]| Body::ListCommittedOffsetsOk {
+            offsets: offsets.iter().map(|(key, offset)| (key.to_string(), *offset)).collect(),
+        };
+
+        record(&mut history, "n0", 1, (0, 1), list(&["a"]), listed(&[("a", 3)]));
+        record(&mut history, "n0", 2, (2, 3), list(&["b"]), listed(&[("b", 1)]));
+        record(&mut history, "n1", 3, (4, 5), list(&["a", "b"]), listed(&[("a", 3), ("b", 2)]));
+        assert!(kafka(&history).is_ok());
+
+        // (but a key that was asked for, and has gone missing, has regressed)
+        record(&mut history, "n1", 4, (6, 7), list(&["a", "b"]), listed(&[("b", 2)]));
+        assert_eq!(kafka(&history).unwrap_err(), vec![
+            Violation::CommitRegressed { key: "a".to_string(), from: 3, to: 0 },
+            Violation::CommitRegressed { key: "a".to_string(), from: 3, to: 0 },
+        ]);
+    }
+}
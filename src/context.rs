@@ -0,0 +1,136 @@
+use std::{sync::{atomic::{AtomicU64, Ordering}, Arc, OnceLock}, time::{Duration, Instant}};
+use tokio::sync::mpsc;
+
+use crate::{Tag, data_models::*, rpc::{RpcClient, RpcError}};
+
+
+/// Where `Context::elapsed()` gets its time from.
+#[derive(Clone)]
+pub(crate) enum Clock {
+    /// wall-clock time, since the runner started.
+    Real(Instant),
+    /// nanoseconds of virtual time, kept up to date by a `sim::Cluster`.
+    Virtual(Arc<AtomicU64>),
+}
+
+impl Clock {
+    fn elapsed(&self) -> Duration {
+        match self {
+            Clock::Real(start) => start.elapsed(),
+            Clock::Virtual(nanos) => Duration::from_nanos(nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A one-shot timer requested via `Context::schedule_after()`.
+pub(crate) struct Timer {
+    pub tag: Tag,
+    pub after: Duration,
+    /// index of the handler that scheduled it, if it was scheduled from a handler's context.
+    pub handler: Option<usize>,
+}
+
+
+/// Everything a handler needs to talk to the rest of the cluster, outside of its return values.
+///
+/// A context is passed into every handler callback, and is cheap to clone, so it can be
+/// moved into spawned tasks.
+#[derive(Clone)]
+pub struct Context {
+    client: RpcClient,
+    // only known once the node has been sent `init`
+    node_ids: Arc<OnceLock<Vec<NodeId>>>,
+    clock: Clock,
+    timer_tx: mpsc::UnboundedSender<Timer>,
+    // `msg_id` of the message being handled, if any
+    msg_id: Option<MsgId>,
+    // index of the handler this context was passed to, if any
+    handler: Option<usize>,
+}
+
+impl Context {
+    pub(crate) fn new(client: RpcClient, node_ids: Vec<NodeId>, clock: Clock, timer_tx: mpsc::UnboundedSender<Timer>) -> Self {
+        let ctx = Self::unassigned(client, clock, timer_tx);
+        let _ = ctx.node_ids.set(node_ids);
+        ctx
+    }
+
+    /// a context for a node that hasn't been sent `init` yet.  (see `assign()`)
+    pub(crate) fn unassigned(client: RpcClient, clock: Clock, timer_tx: mpsc::UnboundedSender<Timer>) -> Self {
+        Self { client, node_ids: Arc::new(OnceLock::new()), clock, timer_tx, msg_id: None, handler: None }
+    }
+
+    /// a copy of this context for the runner's `idx`th handler, so the timers it schedules only fire there.
+    pub(crate) fn for_handler(&self, idx: usize) -> Self {
+        Self { handler: Some(idx), ..self.clone() }
+    }
+
+    /// a copy of this context for handling `msg`.  (see `msg_id()`)
+    pub(crate) fn for_msg(&self, msg: &NodeMessage) -> Self {
+        Self { msg_id: msg.header.msg_id, ..self.clone() }
+    }
+
+    /// fills in the details from `init`, for this context and every clone of it.
+    pub(crate) fn assign(&self, node_id: NodeId, node_ids: Vec<NodeId>) {
+        self.client.assign_node_id(node_id);
+        let _ = self.node_ids.set(node_ids);
+    }
+
+    /// a context that isn't attached to a runner, ie for calling a handler directly in a unit test.
+    ///
+    /// Anything sent (or scheduled) through it is dropped.
+    pub fn detached(node_id: &str, node_ids: Vec<NodeId>) -> Self {
+        let (msg_tx, _) = mpsc::unbounded_channel();
+        let (timer_tx, _) = mpsc::unbounded_channel();
+        Self::new(RpcClient::new(node_id.to_string(), msg_tx), node_ids, Clock::Real(Instant::now()), timer_tx)
+    }
+
+    /// NodeId of this node.  (empty until the node has been sent `init`)
+    pub fn node_id(&self) -> &NodeId {
+        self.client.node_id()
+    }
+
+    /// NodeId's of all the nodes in our 'network' (including this one).  (empty until the node has been sent `init`)
+    pub fn node_ids(&self) -> &[NodeId] {
+        self.node_ids.get().map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// `msg_id` of the message being handled.  (`None` outside of `handle_msg()`, or if the sender didn't set one)
+    pub fn msg_id(&self) -> Option<MsgId> {
+        self.msg_id
+    }
+
+    /// time elapsed since the runner started (or virtual time, in a `sim::Cluster`).
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// the client messages are sent through, ie for a `services::KvClient`.
+    pub fn client(&self) -> &RpcClient {
+        &self.client
+    }
+
+    /// sends `body` to `dest`, and returns the `msg_id` it was sent with.
+    pub fn send(&self, dest: &str, body: Body) -> MsgId {
+        self.client.send(NodeMessage::new(self.node_id().clone(), dest.to_string(), body))
+    }
+
+    /// replies to `msg` with `body`, and returns the `msg_id` the reply was sent with.
+    pub fn reply(&self, msg: &NodeMessage, body: Body) -> MsgId {
+        self.client.send(msg.reply(body))
+    }
+
+    /// sends `body` to `dest` as a request, and waits for the matching reply.
+    pub async fn rpc(&self, dest: &str, body: Body, timeout: Duration) -> Result<NodeMessage, RpcError> {
+        let msg = NodeMessage::new(self.node_id().clone(), dest.to_string(), body);
+        self.client.rpc(msg, timeout).await
+    }
+
+    /// fires `handle_interval(tag)` once, after `after` has passed, on the handler this context was passed to.
+    ///
+    /// (scheduled through a context that wasn't passed to a handler, ie `NodeRunner::context()`,
+    /// it fires on every handler, the same as an interval)
+    pub fn schedule_after(&self, tag: Tag, after: Duration) {
+        let _ = self.timer_tx.send(Timer { tag, after, handler: self.handler });
+    }
+}
@@ -0,0 +1,641 @@
+use std::{collections::{HashMap, HashSet}, hash::Hash};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::{data_models::NodeId, gossip::Mergeable};
+
+//
+// State based CRDTs, for replicating state between nodes without any coordination.
+//
+// Every type here is `Mergeable`, so it can be gossiped with `gossip::Gossip` as is, and
+// (de)serializes to plain json, so it can also be carried in a `Body::Custom` directly.
+//
+
+
+/// A grow-only counter: each node only ever increments its own entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GCounter {
+    counts: HashMap<NodeId, u64>,
+}
+
+impl GCounter {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn increment(&mut self, node_id: &str, by: u64) {
+        *self.counts.entry(node_id.to_string()).or_default() += by;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl Mergeable for GCounter {
+    fn merge(&mut self, other: &Self) {
+        for (node_id, count) in &other.counts {
+            let ours = self.counts.entry(node_id.clone()).or_default();
+            *ours = (*ours).max(*count);
+        }
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        let counts: HashMap<_, _> = self.counts.iter()
+            .filter(|(node_id, count)| known.counts.get(*node_id).is_none_or(|known| known < count))
+            .map(|(node_id, count)| (node_id.clone(), *count))
+            .collect();
+        (!counts.is_empty()).then_some(Self { counts })
+    }
+}
+
+
+/// A counter that can go up and down, as a pair of `GCounter`s.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PNCounter {
+    #[serde(rename = "p")]
+    increments: GCounter,
+    #[serde(rename = "n")]
+    decrements: GCounter,
+}
+
+impl PNCounter {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn add(&mut self, node_id: &str, delta: i64) {
+        match delta >= 0 {
+            true => self.increments.increment(node_id, delta.unsigned_abs()),
+            false => self.decrements.increment(node_id, delta.unsigned_abs()),
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Mergeable for PNCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        let increments = self.increments.delta(&known.increments);
+        let decrements = self.decrements.delta(&known.decrements);
+        if increments.is_none() && decrements.is_none() { return None; }
+
+        Some(Self { increments: increments.unwrap_or_default(), decrements: decrements.unwrap_or_default() })
+    }
+}
+
+
+/// A grow-only set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: Eq + Hash + Deserialize<'de>"))]
+pub struct GSet<T: Eq + Hash> {
+    elements: HashSet<T>,
+}
+
+impl<T: Eq + Hash> Default for GSet<T> {
+    fn default() -> Self { Self { elements: HashSet::new() } }
+}
+
+impl<T: Eq + Hash> GSet<T> {
+    pub fn new() -> Self { Self::default() }
+
+    /// returns `false` if `value` was already in the set.
+    pub fn insert(&mut self, value: T) -> bool {
+        self.elements.insert(value)
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.elements.contains(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+
+    pub fn as_set(&self) -> &HashSet<T> {
+        &self.elements
+    }
+}
+
+impl<T: Eq + Hash> FromIterator<T> for GSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self { elements: iter.into_iter().collect() }
+    }
+}
+
+impl<T: Clone + Eq + Hash + Serialize + DeserializeOwned> Mergeable for GSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.elements.merge(&other.elements);
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        self.elements.delta(&known.elements).map(|elements| Self { elements })
+    }
+}
+
+
+/// A set where an element can be removed, but never re-added once it has been.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: Eq + Hash + Deserialize<'de>"))]
+pub struct TwoPSet<T: Eq + Hash> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Eq + Hash> Default for TwoPSet<T> {
+    fn default() -> Self { Self { added: GSet::new(), removed: GSet::new() } }
+}
+
+impl<T: Clone + Eq + Hash> TwoPSet<T> {
+    pub fn new() -> Self { Self::default() }
+
+    /// returns `false` if `value` is already in the set, or has been removed from it.
+    pub fn insert(&mut self, value: T) -> bool {
+        if self.removed.contains(&value) { return false; }
+        self.added.insert(value)
+    }
+
+    /// returns `false` if `value` wasn't in the set.
+    pub fn remove(&mut self, value: &T) -> bool {
+        if !self.contains(value) { return false; }
+        self.removed.insert(value.clone())
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.added.contains(value) && !self.removed.contains(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.added.iter().filter(|value| !self.removed.contains(value))
+    }
+}
+
+impl<T: Clone + Eq + Hash + Serialize + DeserializeOwned> Mergeable for TwoPSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        let added = self.added.delta(&known.added);
+        let removed = self.removed.delta(&known.removed);
+        if added.is_none() && removed.is_none() { return None; }
+
+        Some(Self { added: added.unwrap_or_default(), removed: removed.unwrap_or_default() })
+    }
+}
+
+
+/// Uniquely identifies a single `ORSet::insert()`: the node that made it, and that node's count of inserts so far.
+pub type Dot = (NodeId, u64);
+
+/// An observed-remove set: an element can be removed and re-added any number of times.
+///
+/// A remove only undoes the inserts this replica has seen, so an insert that races a remove wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: Eq + Hash + Deserialize<'de>"))]
+pub struct ORSet<T: Eq + Hash> {
+    // (a list of pairs on the wire, since json map keys must be strings)
+    #[serde(with = "pairs")]
+    entries: HashMap<T, HashSet<Dot>>,
+    removed: HashSet<Dot>,
+    // inserts made by each node so far
+    clock: HashMap<NodeId, u64>,
+}
+
+impl<T: Eq + Hash> Default for ORSet<T> {
+    fn default() -> Self { Self { entries: HashMap::new(), removed: HashSet::new(), clock: HashMap::new() } }
+}
+
+impl<T: Clone + Eq + Hash> ORSet<T> {
+    pub fn new() -> Self { Self::default() }
+
+    /// inserts `value`, as an insert made by `node_id`.
+    pub fn insert(&mut self, node_id: &str, value: T) {
+        let count = self.clock.entry(node_id.to_string()).or_default();
+        *count += 1;
+        self.entries.entry(value).or_default().insert((node_id.to_string(), *count));
+    }
+
+    /// removes `value`.  Returns `false` if it wasn't in the set.
+    pub fn remove(&mut self, value: &T) -> bool {
+        let Some(dots) = self.entries.remove(value) else { return false };
+        self.removed.extend(dots);
+        true
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.entries.contains_key(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.keys()
+    }
+}
+
+impl<T: Clone + Eq + Hash + Serialize + DeserializeOwned> Mergeable for ORSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.removed.merge(&other.removed);
+        for (value, dots) in &other.entries {
+            self.entries.entry(value.clone()).or_default().merge(dots);
+        }
+        // (an element is only in the set while it has an insert that hasn't been removed)
+        self.entries.retain(|_, dots| {
+            dots.retain(|dot| !self.removed.contains(dot));
+            !dots.is_empty()
+        });
+        for (node_id, count) in &other.clock {
+            let ours = self.clock.entry(node_id.clone()).or_default();
+            *ours = (*ours).max(*count);
+        }
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        let entries: HashMap<_, _> = self.entries.iter()
+            .filter_map(|(value, dots)| {
+                // (inserts `known` has seen removed are as good as known)
+                let unknown: HashSet<Dot> = dots.iter()
+                    .filter(|dot| !known.removed.contains(*dot))
+                    .filter(|dot| known.entries.get(value).is_none_or(|known_dots| !known_dots.contains(*dot)))
+                    .cloned()
+                    .collect();
+                (!unknown.is_empty()).then(|| (value.clone(), unknown))
+            })
+            .collect();
+        let removed = self.removed.delta(&known.removed).unwrap_or_default();
+        if entries.is_empty() && removed.is_empty() { return None; }
+
+        Some(Self { entries, removed, clock: self.clock.clone() })
+    }
+}
+
+
+/// A register where the write with the latest timestamp wins.
+///
+/// Timestamps are supplied by the caller (ie a Lamport clock, or `Context::elapsed()` if clocks
+/// are close enough), and ties are broken by node id, so every replica picks the same winner.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LWWRegister<T> {
+    value: Option<T>,
+    at: u64,
+    node_id: NodeId,
+}
+
+impl<T> Default for LWWRegister<T> {
+    fn default() -> Self { Self { value: None, at: 0, node_id: NodeId::new() } }
+}
+
+impl<T> LWWRegister<T> {
+    pub fn new() -> Self { Self::default() }
+
+    /// writes `value`, unless a later write has already been seen.  Returns `false` if it was ignored.
+    pub fn set(&mut self, node_id: &str, at: u64, value: T) -> bool {
+        self.write(node_id, at, Some(value))
+    }
+
+    /// clears the value, unless a later write has already been seen.
+    pub fn clear(&mut self, node_id: &str, at: u64) -> bool {
+        self.write(node_id, at, None)
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    /// the timestamp of the winning write.
+    pub fn timestamp(&self) -> u64 {
+        self.at
+    }
+
+    fn write(&mut self, node_id: &str, at: u64, value: Option<T>) -> bool {
+        if (at, node_id) <= (self.at, self.node_id.as_str()) { return false; }
+
+        self.value = value;
+        self.at = at;
+        self.node_id = node_id.to_string();
+        true
+    }
+
+    fn is_newer_than(&self, other: &Self) -> bool {
+        (self.at, &self.node_id) > (other.at, &other.node_id)
+    }
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Mergeable for LWWRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        if other.is_newer_than(self) {
+            *self = other.clone();
+        }
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        self.is_newer_than(known).then(|| self.clone())
+    }
+}
+
+
+/// A map of `LWWRegister`s: each key is written (or removed) independently, and the latest write wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(serialize = "K: Serialize, V: Serialize", deserialize = "K: Eq + Hash + Deserialize<'de>, V: Deserialize<'de>"))]
+pub struct LWWMap<K: Eq + Hash, V> {
+    // (removed keys keep their register, so an older write can't bring them back)
+    #[serde(with = "pairs")]
+    entries: HashMap<K, LWWRegister<V>>,
+}
+
+impl<K: Eq + Hash, V> Default for LWWMap<K, V> {
+    fn default() -> Self { Self { entries: HashMap::new() } }
+}
+
+impl<K: Clone + Eq + Hash, V> LWWMap<K, V> {
+    pub fn new() -> Self { Self::default() }
+
+    /// writes `value` for `key`.  (see `LWWRegister::set()`)
+    pub fn insert(&mut self, node_id: &str, at: u64, key: K, value: V) -> bool {
+        self.entries.entry(key).or_default().set(node_id, at, value)
+    }
+
+    /// removes `key`.  (see `LWWRegister::clear()`)
+    pub fn remove(&mut self, node_id: &str, at: u64, key: K) -> bool {
+        self.entries.entry(key).or_default().clear(node_id, at)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).and_then(LWWRegister::get)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().filter_map(|(key, register)| Some((key, register.get()?)))
+    }
+}
+
+impl<K, V> Mergeable for LWWMap<K, V>
+where
+    K: Clone + Eq + Hash + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    fn merge(&mut self, other: &Self) {
+        for (key, register) in &other.entries {
+            self.entries.entry(key.clone()).or_default().merge(register);
+        }
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        let entries: HashMap<_, _> = self.entries.iter()
+            .filter_map(|(key, register)| {
+                let delta = match known.entries.get(key) {
+                    Some(known) => register.delta(known)?,
+                    None => register.clone(),
+                };
+                Some((key.clone(), delta))
+            })
+            .collect();
+        (!entries.is_empty()).then_some(Self { entries })
+    }
+}
+
+
+/// A map whose keys are an `ORSet`, and whose values are themselves `Mergeable` (ie a `GCounter` per key).
+///
+/// Concurrent updates to the same key are merged, rather than one of them winning.  Removing a key
+/// hides it, but its value is kept, so re-adding it picks up where it left off.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(serialize = "K: Serialize, V: Serialize", deserialize = "K: Eq + Hash + Deserialize<'de>, V: Deserialize<'de>"))]
+pub struct ORMap<K: Eq + Hash, V> {
+    keys: ORSet<K>,
+    #[serde(with = "pairs")]
+    values: HashMap<K, V>,
+}
+
+impl<K: Eq + Hash, V> Default for ORMap<K, V> {
+    fn default() -> Self { Self { keys: ORSet::default(), values: HashMap::new() } }
+}
+
+impl<K: Clone + Eq + Hash, V: Default> ORMap<K, V> {
+    pub fn new() -> Self { Self::default() }
+
+    /// adds `key` (as an insert made by `node_id`), and updates its value with `update`.
+    pub fn update(&mut self, node_id: &str, key: K, update: impl FnOnce(&mut V)) {
+        if !self.keys.contains(&key) {
+            self.keys.insert(node_id, key.clone());
+        }
+        update(self.values.entry(key).or_default());
+    }
+
+    /// removes `key`.  Returns `false` if it wasn't in the map.
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.keys.remove(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        if !self.keys.contains(key) { return None; }
+        self.values.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.keys.iter().filter_map(|key| Some((key, self.values.get(key)?)))
+    }
+}
+
+impl<K, V> Mergeable for ORMap<K, V>
+where
+    K: Clone + Eq + Hash + Serialize + DeserializeOwned,
+    V: Mergeable,
+{
+    fn merge(&mut self, other: &Self) {
+        self.keys.merge(&other.keys);
+        for (key, value) in &other.values {
+            self.values.entry(key.clone()).or_default().merge(value);
+        }
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        let keys = self.keys.delta(&known.keys);
+        let values: HashMap<_, _> = self.values.iter()
+            .filter_map(|(key, value)| {
+                let delta = match known.values.get(key) {
+                    Some(known) => value.delta(known)?,
+                    None => value.clone(),
+                };
+                Some((key.clone(), delta))
+            })
+            .collect();
+        if keys.is_none() && values.is_empty() { return None; }
+
+        Some(Self { keys: keys.unwrap_or_default(), values })
+    }
+}
+
+
+/// (de)serializes a `HashMap` as a list of `[key, value]` pairs, so keys don't have to be strings.
+mod pairs {
+    use std::{collections::HashMap, hash::Hash};
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<K: Serialize, V: Serialize, S: Serializer>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Eq + Hash + Deserialize<'de>,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+
+#[cfg(test)]
+mod crdt_tests {
+    use super::*;
+
+    /// merges `a` and `b` both ways round, and checks they end up the same.
+    fn converge<S: Mergeable + PartialEq + std::fmt::Debug>(a: &S, b: &S) -> S {
+        let mut ab = a.clone();
+        ab.merge(b);
+        let mut ba = b.clone();
+        ba.merge(a);
+        assert_eq!(ab, ba, "merge should be commutative");
+
+        let mut again = ab.clone();
+        again.merge(b);
+        assert_eq!(again, ab, "merge should be idempotent");
+        ab
+    }
+
+    /// checks that merging `a`'s delta (against `b`) into `b` catches it up with `a`, and survives a round trip through json.
+    fn delta_catches_up<S: Mergeable + PartialEq + std::fmt::Debug>(a: &S, b: &S) {
+        let delta = a.delta(b).expect("a should know something b doesn't");
+        let delta: S = serde_json::from_value(serde_json::to_value(&delta).unwrap()).unwrap();
+
+        let mut caught_up = b.clone();
+        caught_up.merge(&delta);
+        assert_eq!(caught_up, converge(a, b));
+        assert!(a.delta(&caught_up).is_none());
+    }
+
+    #[test]
+    fn counters_converge() {
+        let (mut a, mut b) = (GCounter::new(), GCounter::new());
+        a.increment("n1", 3);
+        b.increment("n1", 1);
+        b.increment("n2", 2);
+        assert_eq!(converge(&a, &b).value(), 5);
+        delta_catches_up(&a, &b);
+        assert_eq!(a.delta(&b), Some(GCounter { counts: HashMap::from([("n1".to_string(), 3)]) }));
+
+        let (mut a, mut b) = (PNCounter::new(), PNCounter::new());
+        a.add("n1", 5);
+        a.add("n1", -2);
+        b.add("n2", -4);
+        assert_eq!(converge(&a, &b).value(), -1);
+        delta_catches_up(&a, &b);
+    }
+
+    #[test]
+    fn sets_converge() {
+        let a: GSet<usize> = [1, 2].into_iter().collect();
+        let b: GSet<usize> = [2, 3].into_iter().collect();
+        assert_eq!(converge(&a, &b).len(), 3);
+        delta_catches_up(&a, &b);
+
+        let (mut a, mut b) = (TwoPSet::new(), TwoPSet::new());
+        a.insert(1usize);
+        b.merge(&a);
+        b.remove(&1);
+        assert!(!converge(&a, &b).contains(&1));
+        assert!(!b.insert(1), "a removed element can't be re-added");
+        delta_catches_up(&b, &a);
+    }
+
+    #[test]
+    fn or_set_insert_wins_over_a_concurrent_remove() {
+        let mut a = ORSet::new();
+        a.insert("n1", "x".to_string());
+        let mut b = a.clone();
+
+        // b removes the insert it has seen, while a inserts it again
+        assert!(b.remove(&"x".to_string()));
+        a.insert("n1", "x".to_string());
+
+        let merged = converge(&a, &b);
+        assert!(merged.contains(&"x".to_string()));
+        delta_catches_up(&a, &b);
+
+        // (once the remove has seen every insert, it sticks)
+        let mut c = merged.clone();
+        c.remove(&"x".to_string());
+        assert!(!converge(&c, &merged).contains(&"x".to_string()));
+    }
+
+    #[test]
+    fn latest_write_wins() {
+        let (mut a, mut b) = (LWWRegister::new(), LWWRegister::new());
+        a.set("n1", 1, 10i64);
+        b.set("n2", 2, 20i64);
+        assert!(!b.set("n1", 1, 30));
+        assert_eq!(converge(&a, &b).get(), Some(&20));
+        delta_catches_up(&b, &a);
+        assert!(a.delta(&b).is_none());
+
+        let (mut a, mut b) = (LWWMap::new(), LWWMap::new());
+        a.insert("n1", 1, 7usize, 70i64);
+        b.insert("n2", 2, 7usize, 71i64);
+        b.insert("n2", 1, 8usize, 80i64);
+        a.remove("n1", 3, 8usize);
+        let merged = converge(&a, &b);
+        assert_eq!(merged.get(&7), Some(&71));
+        assert_eq!(merged.get(&8), None);
+        delta_catches_up(&a, &b);
+    }
+
+    #[test]
+    fn or_map_merges_concurrent_updates() {
+        let (mut a, mut b) = (ORMap::<String, GCounter>::new(), ORMap::new());
+        a.update("n1", "k".to_string(), |counter| counter.increment("n1", 2));
+        b.update("n2", "k".to_string(), |counter: &mut GCounter| counter.increment("n2", 3));
+        b.update("n2", "j".to_string(), |counter: &mut GCounter| counter.increment("n2", 1));
+
+        let merged = converge(&a, &b);
+        assert_eq!(merged.get(&"k".to_string()).map(GCounter::value), Some(5));
+        delta_catches_up(&b, &a);
+
+        let mut removed = merged.clone();
+        assert!(removed.remove(&"j".to_string()));
+        assert_eq!(converge(&removed, &merged).get(&"j".to_string()), None);
+    }
+
+    #[test]
+    fn serializes_to_plain_json() {
+        let mut counter = PNCounter::new();
+        counter.add("n1", 2);
+        counter.add("n2", -1);
+        assert_eq!(serde_json::to_value(&counter).unwrap(), serde_json::json!({"p": {"n1": 2}, "n": {"n2": 1}}));
+
+        let mut set = ORSet::new();
+        set.insert("n1", 4usize);
+        assert_eq!(serde_json::to_value(&set).unwrap(), serde_json::json!({"entries": [[4, [["n1", 1]]]], "removed": [], "clock": {"n1": 1}}));
+    }
+}
@@ -1,60 +1,171 @@
 use std::{fmt::Display, collections::{HashMap, HashSet}};
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Serializer, Deserializer, ser::{self, SerializeTuple}, de::{self, Visitor, SeqAccess, DeserializeOwned}};
+use serde_json::{Map, Value};
 
 pub type MsgId = usize;
 pub type NodeId = String;
 
-#[derive(Deserialize, Serialize, Debug)]
+/// A message as it travels between nodes / clients.
+/// 
+/// On the wire, the `header` fields sit alongside the `body` fields:
+/// `{"src": .., "dest": .., "body": {"type": .., "msg_id": .., "in_reply_to": .., ..}}`
+#[derive(Debug, Clone)]
 pub struct NodeMessage {
     pub src: NodeId,
     pub dest: NodeId,
+    pub header: Header,
     pub body: Body,
 }
 
+/// The bookkeeping fields common to every message body.
+/// 
+/// `msg_id` is assigned by the `NodeRunner` when a message is sent, 
+/// and `in_reply_to` links a reply back to the request that caused it.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Header {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<MsgId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<MsgId>,
+}
+
 impl NodeMessage {
-    pub(crate) fn as_node_type(&self) -> Option<NodeType> {
-        let msg_type: NodeType;
+    /// create a new (non-reply) message.  The `msg_id` is filled in when it is sent.
+    pub fn new(src: NodeId, dest: NodeId, body: Body) -> Self {
+        Self { src, dest, header: Header::default(), body }
+    }
+
+    /// builds a reply to this message: `src`/`dest` are swapped, and `in_reply_to` is set to this message's `msg_id`.
+    pub fn reply(&self, body: Body) -> NodeMessage {
+        NodeMessage {
+            src: self.dest.clone(),
+            dest: self.src.clone(),
+            header: Header { msg_id: None, in_reply_to: self.header.msg_id },
+            body,
+        }
+    }
+
+    /// builds an `error` reply to this message.
+    /// 
+    /// Returns `None` if this message has no `msg_id` to reply to.
+    pub fn error_reply(&self, code: ErrorCode, text: impl Into<String>) -> Option<NodeMessage> {
+        self.header.msg_id?;
+        Some(self.reply(Body::Error { code, text: text.into() }))
+    }
+
+    /// the 'NodeType's that could handle this message, in order of preference.
+    /// 
+    /// Some message types are shared between workloads (ie `read`), so the runner
+    /// dispatches to the first of these that has a registered handler.
+    pub(crate) fn as_node_types(&self) -> Vec<NodeType> {
         match &self.body {
             // echo messages
-            Body::Echo { msg_id: _, echo: _ } => msg_type = NodeType::Echo,
+            Body::Echo { .. } => vec![NodeType::Echo],
 
             // generate messages
-            Body::Generate { msg_id: _ } => msg_type = NodeType::Generate,
+            Body::Generate => vec![NodeType::Generate],
             
             // broadcast messages
-            Body::Topology { msg_id: _, topology: _ } => msg_type = NodeType::Broadcast,
-            Body::Broadcast { msg_id: _, message: _ } => msg_type = NodeType::Broadcast,
-            Body::Read { msg_id: _ } => msg_type = NodeType::Broadcast,
-            Body::ReadOk { msg_id: _, in_reply_to: _, messages: _ } => msg_type = NodeType::Broadcast,
+            Body::Topology { .. } => vec![NodeType::Broadcast],
+            Body::Broadcast { .. } => vec![NodeType::Broadcast],
+            // (acks from peers, for nodes that track which broadcasts were delivered)
+            Body::BroadcastOk => vec![NodeType::Broadcast],
+            Body::ReadOk { messages: Some(_), .. } => vec![NodeType::Broadcast],
+
+            // counter messages
+            Body::Add { .. } => vec![NodeType::Counter, NodeType::PnCounter],
+
+            // kafka messages
+            Body::Send { .. } => vec![NodeType::Kafka],
+            Body::Poll { .. } => vec![NodeType::Kafka],
+            Body::CommitOffsets { .. } => vec![NodeType::Kafka],
+            Body::ListCommittedOffsets { .. } => vec![NodeType::Kafka],
+
+            // txn messages
+            Body::Txn { .. } => vec![NodeType::Txn],
+
+            // user defined messages are dispatched by their `type`
+            Body::Custom(custom) => vec![NodeType::Custom(custom.kind.clone())],
+
+            // shared between workloads
+            Body::Read { key: None } => vec![NodeType::Broadcast, NodeType::Counter, NodeType::PnCounter],
+
+            _ => vec![],
+        }
+    }
+}
 
-            _ => return None,
+// (de)serialize through a 'wire' layout, where the header is flattened into the body.
+
+#[derive(Serialize)]
+struct WireMessageRef<'a> {
+    src: &'a NodeId,
+    dest: &'a NodeId,
+    body: WireBodyRef<'a>,
+}
+
+#[derive(Serialize)]
+struct WireBodyRef<'a> {
+    #[serde(flatten)]
+    header: &'a Header,
+    #[serde(flatten)]
+    body: &'a Body,
+}
+
+#[derive(Deserialize)]
+struct WireMessage {
+    src: NodeId,
+    dest: NodeId,
+    body: WireBody,
+}
+
+#[derive(Deserialize)]
+struct WireBody {
+    #[serde(flatten)]
+    header: Header,
+    #[serde(flatten)]
+    body: Body,
+}
+
+impl Serialize for NodeMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        WireMessageRef {
+            src: &self.src,
+            dest: &self.dest,
+            body: WireBodyRef { header: &self.header, body: &self.body },
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let WireMessage { src, dest, body: WireBody { header, body } } = WireMessage::deserialize(deserializer)?;
+
+        // a built-in `type` only falls through to `Custom` when its fields don't match
+        if let Body::Custom(custom) = &body {
+            if BUILTIN_TYPES.contains(&custom.kind.as_str()) {
+                return Err(de::Error::custom(format!("invalid fields for a `{}` message", custom.kind)));
+            }
         }
-        
-        Some(msg_type)
+        Ok(NodeMessage { src, dest, header, body })
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// (a new built-in variant also needs its `type` added to the `builtin_types!` table below)
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Body {
     // Echo types
     Echo { 
-        msg_id: MsgId, 
         echo: String,
     },
     EchoOk {
-        msg_id: MsgId,
-        in_reply_to: MsgId,
         echo: String,
      },
      
      // Generate Unique ID
-     Generate { 
-         msg_id: MsgId, 
-     },
+     Generate,
      GenerateOk {
-         msg_id: MsgId,
-         in_reply_to: MsgId,
          id: String,
       },
 
@@ -62,57 +173,366 @@ pub enum Body {
      // - Topology / TopologyOk
      // - Broadcast / BroadcastOk
      // - Read / ReadOk
+     //
+     // Counter Workloads (g-counter / pn-counter) :
+     // - Add / AddOk  (a pn-counter's `delta` can be negative)
+     // - Read / ReadOk (with a `value`)
+     //
+     // Kafka Workload :
+     // - Send / SendOk
+     // - Poll / PollOk
+     // - CommitOffsets / CommitOffsetsOk
+     // - ListCommittedOffsets / ListCommittedOffsetsOk
+     //
+     // Transaction Workload (txn-rw-register) :
+     // - Txn / TxnOk
+     //
+     // KV Services (lin-kv, seq-kv, lww-kv) :
+     // - Read / ReadOk (with a `key` / `value`)
+     // - Write / WriteOk
+     // - Cas / CasOk
      Topology { 
-         msg_id: MsgId,
          topology: HashMap<NodeId, Vec<NodeId>>,
      },
-     TopologyOk {
-         msg_id: MsgId,
-         in_reply_to: MsgId,
-      },
+     TopologyOk,
      Broadcast { 
-         msg_id: MsgId, 
          message: usize,
      },
-     BroadcastOk {
-         msg_id: MsgId,
-         in_reply_to: MsgId,
-      },
+     BroadcastOk,
      Read { 
-         msg_id: MsgId,
+         #[serde(default, skip_serializing_if = "Option::is_none")]
+         key: Option<Value>,
      },
      ReadOk {
-         msg_id: MsgId,
-         in_reply_to: MsgId,
-         messages: HashSet<usize>,
+         #[serde(default, skip_serializing_if = "Option::is_none")]
+         messages: Option<HashSet<usize>>,
+         #[serde(default, skip_serializing_if = "Option::is_none")]
+         value: Option<Value>,
+      },
+     Add {
+         delta: i64,
+     },
+     AddOk,
+     Send {
+         key: String,
+         msg: i64,
+     },
+     SendOk {
+         offset: usize,
+      },
+     Poll {
+         offsets: HashMap<String, usize>,
+     },
+     PollOk {
+         /// `[offset, msg]` pairs for each requested key
+         msgs: HashMap<String, Vec<(usize, i64)>>,
+      },
+     CommitOffsets {
+         offsets: HashMap<String, usize>,
+     },
+     CommitOffsetsOk,
+     ListCommittedOffsets {
+         keys: Vec<String>,
+     },
+     ListCommittedOffsetsOk {
+         offsets: HashMap<String, usize>,
+      },
+     Txn {
+         txn: Vec<MicroOp>,
+     },
+     TxnOk {
+         txn: Vec<MicroOp>,
+      },
+     Write {
+         key: Value,
+         value: Value,
+     },
+     WriteOk,
+     Cas {
+         key: Value,
+         from: Value,
+         to: Value,
+         #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+         create_if_not_exists: bool,
+     },
+     CasOk,
+
+     // Errors:
+     // - can be sent in reply to any request
+     Error {
+         code: ErrorCode,
+         #[serde(default)]
+         text: String,
       },
-     // ... TODO: fill in the rest of the types.
+
+     // User defined messages:
+     // - any message `type` not covered above
+     #[serde(untagged)]
+     Custom(CustomBody),
+}
+
+
+/// declares `BUILTIN_TYPES` and `Body::kind()` from the one table of variants and their `type`s.
+///
+/// `kind()`'s match has no wildcard, so a new variant won't compile until it's added here,
+/// which also adds it to `BUILTIN_TYPES`.
+macro_rules! builtin_types {
+    ($($variant:ident => $kind:literal),* $(,)?) => {
+        /// the `type` of every built-in `Body` variant.
+        ///
+        /// (a message with one of these types, but the wrong fields, is rejected rather than parsed as `Body::Custom`)
+        const BUILTIN_TYPES: &[&str] = &[$($kind),*];
+
+        impl Body {
+            /// the message's `type`, as it appears on the wire.
+            pub fn kind(&self) -> &str {
+                match self {
+                    $(Body::$variant { .. } => $kind,)*
+                    Body::Custom(custom) => &custom.kind,
+                }
+            }
+        }
+    };
 }
 
+builtin_types! {
+    Echo => "echo",
+    EchoOk => "echo_ok",
+    Generate => "generate",
+    GenerateOk => "generate_ok",
+    Topology => "topology",
+    TopologyOk => "topology_ok",
+    Broadcast => "broadcast",
+    BroadcastOk => "broadcast_ok",
+    Read => "read",
+    ReadOk => "read_ok",
+    Add => "add",
+    AddOk => "add_ok",
+    Send => "send",
+    SendOk => "send_ok",
+    Poll => "poll",
+    PollOk => "poll_ok",
+    CommitOffsets => "commit_offsets",
+    CommitOffsetsOk => "commit_offsets_ok",
+    ListCommittedOffsets => "list_committed_offsets",
+    ListCommittedOffsetsOk => "list_committed_offsets_ok",
+    Txn => "txn",
+    TxnOk => "txn_ok",
+    Write => "write",
+    WriteOk => "write_ok",
+    Cas => "cas",
+    CasOk => "cas_ok",
+    Error => "error",
+}
 
 impl Body {
-    pub fn set_msg_id(&mut self, new_id: MsgId) {
+    /// converts a user defined payload into a `Body::Custom`.
+    /// 
+    /// (see `CustomBody::new()`)
+    pub fn custom<T: Serialize>(payload: &T) -> Result<Self, serde_json::Error> {
+        Ok(Body::Custom(CustomBody::new(payload)?))
+    }
+}
+
+
+/// The body of a message whose `type` isn't one of `Body`'s built-in variants.
+/// 
+/// This lets handlers declare their own message types as a serde enum 
+/// (tagged with `#[serde(tag = "type")]`), and convert to/from `CustomBody` with `new()` / `parse()`.
+/// Handlers are registered for a custom `type` with `NodeType::Custom`.
+/// 
+/// `msg_id` and `in_reply_to` live in the message's `Header`, so the runner can still assign ids and route replies.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CustomBody {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+}
+
+impl CustomBody {
+    /// converts `payload` into a custom body.
+    /// 
+    /// `payload` must serialize to a json object with a string `type` field.
+    pub fn new<T: Serialize>(payload: &T) -> Result<Self, serde_json::Error> {
+        let Value::Object(mut fields) = serde_json::to_value(payload)? else {
+            return Err(ser::Error::custom("custom body must serialize to a json object"));
+        };
+        let Some(Value::String(kind)) = fields.remove("type") else {
+            return Err(ser::Error::custom("custom body must have a string `type` field"));
+        };
+
+        Ok(Self { kind, fields })
+    }
+
+    /// converts this body back into the user's payload type.
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        let mut fields = self.fields.clone();
+        fields.insert("type".to_string(), Value::String(self.kind.clone()));
+
+        serde_json::from_value(Value::Object(fields))
+    }
+}
+
+
+/// A single read or write within a `txn`.
+/// 
+/// On the wire, these are `["r", key, value]` / `["w", key, value]` arrays, 
+/// where a read's value is `null` until the node fills it in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MicroOp {
+    Read { key: usize, value: Option<i64> },
+    Write { key: usize, value: i64 },
+}
+
+impl MicroOp {
+    pub fn key(&self) -> usize {
+        match self {
+            MicroOp::Read { key, value: _ } => *key,
+            MicroOp::Write { key, value: _ } => *key,
+        }
+    }
+}
+
+impl Serialize for MicroOp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        match self {
+            MicroOp::Read { key, value } => {
+                tuple.serialize_element("r")?;
+                tuple.serialize_element(key)?;
+                tuple.serialize_element(value)?;
+            },
+            MicroOp::Write { key, value } => {
+                tuple.serialize_element("w")?;
+                tuple.serialize_element(key)?;
+                tuple.serialize_element(value)?;
+            },
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for MicroOp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MicroOpVisitor;
+
+        impl<'de> Visitor<'de> for MicroOpVisitor {
+            type Value = MicroOp;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a [\"r\" | \"w\", key, value] array")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MicroOp, A::Error> {
+                let op: String = seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let key: usize = seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+
+                match op.as_str() {
+                    "r" => {
+                        let value: Option<i64> = seq.next_element()?
+                            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                        Ok(MicroOp::Read { key, value })
+                    },
+                    "w" => {
+                        let value: i64 = seq.next_element()?
+                            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                        Ok(MicroOp::Write { key, value })
+                    },
+                    other => Err(de::Error::unknown_variant(other, &["r", "w"])),
+                }
+            }
+        }
+
+        deserializer.deserialize_seq(MicroOpVisitor)
+    }
+}
+
+
+/// Maelstrom's standard error codes.
+/// 
+/// Any code not covered by a named variant is carried through as `Custom`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Custom(u32),
+}
+
+impl ErrorCode {
+    /// `true` if the operation that caused this error definitely did not happen.
+    /// 
+    /// (`timeout` and `crash` are indefinite: the operation may or may not have taken place)
+    pub fn is_definite(&self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::Crash)
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            0  => ErrorCode::Timeout,
+            1  => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            other => ErrorCode::Custom(other),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Custom(other) => other,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Body::Echo { msg_id, echo: _ } => 
-                *msg_id = new_id,
-            Body::EchoOk { msg_id, in_reply_to: _, echo: _ } => 
-                *msg_id = new_id,
-            Body::Generate { msg_id } => 
-                *msg_id = new_id,
-            Body::GenerateOk { msg_id, in_reply_to: _, id: _ } => 
-                *msg_id = new_id,
-            Body::Topology { msg_id, topology: _ } => 
-                *msg_id = new_id,
-            Body::TopologyOk { msg_id, in_reply_to: _ } => 
-                *msg_id = new_id,
-            Body::Broadcast { msg_id, message: _ } => 
-                *msg_id = new_id,
-            Body::BroadcastOk { msg_id, in_reply_to: _ } => 
-                *msg_id = new_id,
-            Body::Read { msg_id } => 
-                *msg_id = new_id,
-            Body::ReadOk { msg_id, in_reply_to: _, messages: _ } => 
-                *msg_id = new_id,
+            ErrorCode::Timeout => write!(f, "timeout"),
+            ErrorCode::NodeNotFound => write!(f, "node-not-found"),
+            ErrorCode::NotSupported => write!(f, "not-supported"),
+            ErrorCode::TemporarilyUnavailable => write!(f, "temporarily-unavailable"),
+            ErrorCode::MalformedRequest => write!(f, "malformed-request"),
+            ErrorCode::Crash => write!(f, "crash"),
+            ErrorCode::Abort => write!(f, "abort"),
+            ErrorCode::KeyDoesNotExist => write!(f, "key-does-not-exist"),
+            ErrorCode::KeyAlreadyExists => write!(f, "key-already-exists"),
+            ErrorCode::PreconditionFailed => write!(f, "precondition-failed"),
+            ErrorCode::TxnConflict => write!(f, "txn-conflict"),
+            ErrorCode::Custom(code) => write!(f, "error code {}", code),
         }
     }
 }
@@ -124,7 +544,13 @@ pub enum NodeType {
     Echo,
     Generate,
     Broadcast,
-    // ... TODO: fill in the rest of the types.
+    Counter,
+    PnCounter,
+    Kafka,
+    Txn,
+
+    /// a user defined message, identified by its `type` tag.
+    Custom(String),
 }
 
 impl Display for NodeType {
@@ -133,8 +559,143 @@ impl Display for NodeType {
             NodeType::Echo => write!(f, "echo"),
             NodeType::Generate => write!(f, "generate"),
             NodeType::Broadcast => write!(f, "broadcast"),
+            NodeType::Counter => write!(f, "counter"),
+            NodeType::PnCounter => write!(f, "pn-counter"),
+            NodeType::Kafka => write!(f, "kafka"),
+            NodeType::Txn => write!(f, "txn"),
+            NodeType::Custom(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod data_models_tests {
+    use super::*;
+
+    /// parses `json` as a `NodeMessage`, and checks it serializes back to the same json.
+    fn round_trip(json: &str) -> NodeMessage {
+        let msg: NodeMessage = serde_json::from_str(json).unwrap();
+
+        let expected: Value = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_value(&msg).unwrap(), expected);
+
+        msg
+    }
+
+    #[test]
+    fn header_is_flattened_into_body() {
+        let msg = round_trip(r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hello"}}"#);
+
+        assert_eq!(msg.header, Header { msg_id: Some(1), in_reply_to: None });
+        assert!(matches!(msg.body, Body::Echo { .. }));
+
+        round_trip(r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":2}}"#);
+    }
+
+    #[test]
+    fn reply_swaps_src_and_dest() {
+        let msg = round_trip(r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":7,"echo":"hello"}}"#);
+        let reply = msg.reply(Body::EchoOk { echo: "hello".to_string() });
+
+        assert_eq!(reply.src, "n1");
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.header, Header { msg_id: None, in_reply_to: Some(7) });
+    }
+
+    #[test]
+    fn micro_ops_use_tuple_encoding() {
+        let msg = round_trip(r#"{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":3,"txn":[["r",1,null],["w",1,6],["r",2,9]]}}"#);
+
+        match &msg.body {
+            Body::Txn { txn } => assert_eq!(txn, &vec![
+                MicroOp::Read { key: 1, value: None },
+                MicroOp::Write { key: 1, value: 6 },
+                MicroOp::Read { key: 2, value: Some(9) },
+            ]),
+            _ => panic!("'txn' did not deserialize to a 'Txn' body"),
+        }
+    }
 
-            // ... TODO: fill in the rest of the types.
+    #[test]
+    fn rejects_unknown_micro_op() {
+        let result = serde_json::from_str::<MicroOp>(r#"["append",1,2]"#);
+        assert!(result.is_err());
+    }
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Gossip {
+        Gossip { messages: Vec<usize> },
+        GossipOk { },
+    }
+
+    #[test]
+    fn unknown_types_become_custom_bodies() {
+        let msg = round_trip(r#"{"src":"n2","dest":"n1","body":{"type":"gossip","msg_id":4,"messages":[1,2]}}"#);
+
+        let Body::Custom(custom) = &msg.body else { panic!("'gossip' did not deserialize to a 'Custom' body") };
+        assert_eq!(custom.kind, "gossip");
+        assert_eq!(msg.header.msg_id, Some(4));
+        assert_eq!(custom.parse::<Gossip>().unwrap(), Gossip::Gossip { messages: vec![1, 2] });
+    }
+
+    #[test]
+    fn malformed_builtin_types_are_not_custom() {
+        let parse = |body: &str| serde_json::from_str::<NodeMessage>(&format!(r#"{{"src":"c1","dest":"n1","body":{}}}"#, body));
+
+        assert!(parse(r#"{"type":"echo","msg_id":1}"#).is_err());
+        assert!(parse(r#"{"type":"add","delta":"one"}"#).is_err());
+
+        for kind in BUILTIN_TYPES {
+            if let Ok(msg) = parse(&format!(r#"{{"type":"{}","unexpected":true}}"#, kind)) {
+                assert!(!matches!(msg.body, Body::Custom(_)), "'{}' became a custom body", kind);
+                assert_eq!(msg.body.kind(), *kind);
+            }
         }
     }
+
+    /// one of each built-in `Body` variant.
+    fn every_builtin_body() -> Vec<Body> {
+        let value = || Value::from(1);
+        vec![
+            Body::Echo { echo: String::new() }, Body::EchoOk { echo: String::new() },
+            Body::Generate, Body::GenerateOk { id: String::new() },
+            Body::Topology { topology: HashMap::new() }, Body::TopologyOk,
+            Body::Broadcast { message: 1 }, Body::BroadcastOk,
+            Body::Read { key: None }, Body::ReadOk { messages: None, value: None },
+            Body::Add { delta: 1 }, Body::AddOk,
+            Body::Send { key: String::new(), msg: 1 }, Body::SendOk { offset: 1 },
+            Body::Poll { offsets: HashMap::new() }, Body::PollOk { msgs: HashMap::new() },
+            Body::CommitOffsets { offsets: HashMap::new() }, Body::CommitOffsetsOk,
+            Body::ListCommittedOffsets { keys: Vec::new() }, Body::ListCommittedOffsetsOk { offsets: HashMap::new() },
+            Body::Txn { txn: Vec::new() }, Body::TxnOk { txn: Vec::new() },
+            Body::Write { key: value(), value: value() }, Body::WriteOk,
+            Body::Cas { key: value(), from: value(), to: value(), create_if_not_exists: false }, Body::CasOk,
+            Body::Error { code: ErrorCode::Crash, text: String::new() },
+        ]
+    }
+
+    #[test]
+    fn builtin_types_match_serde_names() {
+        let bodies = every_builtin_body();
+        for body in &bodies {
+            assert_eq!(serde_json::to_value(body).unwrap()["type"], body.kind());
+        }
+
+        let kinds: HashSet<&str> = bodies.iter().map(|body| body.kind()).collect();
+        assert_eq!(kinds, BUILTIN_TYPES.iter().copied().collect());
+    }
+
+    #[test]
+    fn custom_bodies_keep_reply_bookkeeping() {
+        let msg = round_trip(r#"{"src":"n2","dest":"n1","body":{"type":"gossip","msg_id":4,"messages":[1,2]}}"#);
+        assert_eq!(msg.body.kind(), "gossip");
+        let reply = msg.reply(Body::custom(&Gossip::GossipOk {}).unwrap());
+
+        assert_eq!(
+            serde_json::to_value(&reply).unwrap(),
+            serde_json::json!({"src":"n1","dest":"n2","body":{"type":"gossip_ok","in_reply_to":4}}),
+        );
+    }
 }
@@ -0,0 +1,293 @@
+use std::{collections::{BTreeSet, HashMap, HashSet}, hash::{DefaultHasher, Hash, Hasher}};
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Map;
+use tracing::warn;
+
+use crate::{Tag, context::Context, data_models::*};
+
+
+/// how many peers are gossiped with on each interval, by default.
+const DEFAULT_FANOUT: usize = 3;
+
+/// State that can be gossiped between nodes.
+///
+/// `merge()` must be commutative, associative and idempotent, so replicas converge no matter
+/// what order (or how many times) they receive each other's state.
+pub trait Mergeable: Clone + Default + Serialize + DeserializeOwned {
+    /// folds `other` into this state.
+    fn merge(&mut self, other: &Self);
+
+    /// the part of this state that `known` doesn't already cover, or `None` if it covers all of it.
+    fn delta(&self, known: &Self) -> Option<Self>;
+}
+
+impl<T: Clone + Eq + Hash + Serialize + DeserializeOwned> Mergeable for HashSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.extend(other.iter().cloned());
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        let delta: Self = self.difference(known).cloned().collect();
+        (!delta.is_empty()).then_some(delta)
+    }
+}
+
+impl<T: Clone + Ord + Serialize + DeserializeOwned> Mergeable for BTreeSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.extend(other.iter().cloned());
+    }
+
+    fn delta(&self, known: &Self) -> Option<Self> {
+        let delta: Self = self.difference(known).cloned().collect();
+        (!delta.is_empty()).then_some(delta)
+    }
+}
+
+
+/// Anti-entropy gossip of some `Mergeable` state.
+///
+/// Tracks what each peer is believed to know, and on every interval sends a random `fanout`
+/// of peers whatever they're missing.  A peer is only believed to know something once it has
+/// acknowledged it (or sent it to us itself), so anything lost in the network is simply sent again.
+///
+/// The `tag` is both the interval tag gossip is sent on, and the message `type` it's sent as:
+/// the handler should be registered for `node_types()`, and pass every message and interval to
+/// `handle_msg()` / `handle_interval()`.
+#[derive(Debug)]
+pub struct Gossip<S: Mergeable> {
+    tag: Tag,
+    fanout: usize,
+    // seeded from our node id on first use, unless `with_seed()` picked one
+    rng: Option<StdRng>,
+
+    state: S,
+    // none of our peers (until `set_peers()`) means every other node
+    peers: Option<Vec<NodeId>>,
+    // what each peer is believed to know
+    known: HashMap<NodeId, S>,
+    // msg_id -> the peer, and the delta, of the last gossip sent to each peer
+    in_flight: HashMap<MsgId, (NodeId, S)>,
+}
+
+impl<S: Mergeable> Gossip<S> {
+    pub fn new(tag: &str) -> Self {
+        Self {
+            tag: tag.to_string(),
+            fanout: DEFAULT_FANOUT,
+            rng: None,
+            state: S::default(),
+            peers: None,
+            known: HashMap::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// gossips with (at most) `fanout` peers on each interval.
+    pub fn with_fanout(mut self, fanout: usize) -> Self {
+        self.fanout = fanout;
+        self
+    }
+
+    /// seeds the peer selection.
+    ///
+    /// Without this it's seeded from the node id, so a `sim::Cluster` run still replays exactly,
+    /// but makes the same choices for every cluster seed.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Some(StdRng::seed_from_u64(seed));
+        self
+    }
+
+    /// the interval tag, and message `type`, gossip is sent as.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// the message types to register the handler for.
+    pub fn node_types(&self) -> Vec<NodeType> {
+        vec![NodeType::Custom(self.tag.clone()), NodeType::Custom(self.ack_kind())]
+    }
+
+    /// the peers to gossip with.  (all the other nodes, until this is called)
+    pub fn set_peers(&mut self, peers: Vec<NodeId>) {
+        self.peers = Some(peers);
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// merges a local update into the state.
+    pub fn merge(&mut self, update: &S) {
+        self.state.merge(update);
+    }
+
+    /// applies a local update to the state in place, ie for state that can't be built up from a merge.
+    pub fn update(&mut self, update: impl FnOnce(&mut S)) {
+        update(&mut self.state);
+    }
+
+    /// records that `peer` knows `state`, ie because it sent it to us some other way.
+    pub fn mark_known(&mut self, peer: &str, state: &S) {
+        self.known.entry(peer.to_string()).or_default().merge(state);
+    }
+
+    /// what `peer` is believed to know.  (`None` if nothing has been exchanged with it yet)
+    pub fn known_by(&self, peer: &str) -> Option<&S> {
+        self.known.get(peer)
+    }
+
+    /// merges gossip from a peer (replying to acknowledge it), or records a peer's acknowledgement.
+    ///
+    /// Returns `false` for any other message.
+    pub fn handle_msg(&mut self, ctx: &Context, msg: &NodeMessage) -> bool {
+        let Body::Custom(custom) = &msg.body else { return false };
+
+        if custom.kind == self.tag {
+            let Some(state) = custom.fields.get("state").and_then(|state| S::deserialize(state).ok()) else {
+                warn!("ignoring gossip without a valid state: {:?}", msg);
+                return true;
+            };
+
+            self.state.merge(&state);
+            // (whoever sent it obviously knows it)
+            self.mark_known(&msg.src, &state);
+
+            ctx.reply(msg, Body::Custom(CustomBody { kind: self.ack_kind(), fields: Map::new() }));
+            return true;
+        }
+
+        if custom.kind == self.ack_kind() {
+            let acked = msg.header.in_reply_to.and_then(|msg_id| self.in_flight.remove(&msg_id));
+            if let Some((peer, delta)) = acked {
+                self.known.entry(peer).or_default().merge(&delta);
+            }
+            return true;
+        }
+
+        false
+    }
+
+    /// sends each of (up to) `fanout` random peers whatever they don't know yet, if `tag` is this gossip's interval.
+    ///
+    /// Returns `false` (and does nothing) for any other tag, so it can be called for every interval.
+    pub fn handle_interval(&mut self, ctx: &Context, tag: &str) -> bool {
+        if tag != self.tag { return false; }
+
+        let peers: Vec<NodeId> = match &self.peers {
+            Some(peers) => peers.clone(),
+            None => ctx.node_ids().to_vec(),
+        };
+        let peers: Vec<&NodeId> = peers.iter()
+            .filter(|peer| *peer != ctx.node_id())
+            .collect();
+
+        let rng = self.rng.get_or_insert_with(|| {
+            let mut hasher = DefaultHasher::new();
+            ctx.node_id().hash(&mut hasher);
+            StdRng::seed_from_u64(hasher.finish())
+        });
+        for peer in peers.choose_multiple(rng, self.fanout) {
+            let delta = match self.known.get(*peer) {
+                Some(known) => self.state.delta(known),
+                None => self.state.delta(&S::default()),
+            };
+            let Some(delta) = delta else { continue };
+
+            let mut fields = Map::new();
+            fields.insert("state".to_string(), serde_json::to_value(&delta).expect("gossip state should serialize"));
+
+            // (only the latest gossip to each peer is waited on; an ack for an older one is ignored)
+            self.in_flight.retain(|_, (in_flight_to, _)| in_flight_to != *peer);
+            let msg_id = ctx.send(peer, Body::Custom(CustomBody { kind: self.tag.clone(), fields }));
+            self.in_flight.insert(msg_id, ((*peer).clone(), delta));
+        }
+        true
+    }
+
+    fn ack_kind(&self) -> String {
+        format!("{}_ok", self.tag)
+    }
+}
+
+impl<S: Mergeable> Default for Gossip<S> {
+    fn default() -> Self { Self::new("gossip") }
+}
+
+
+#[cfg(test)]
+mod gossip_tests {
+    use super::*;
+    use std::{sync::{Arc, Mutex}, time::Duration};
+    use crate::{NodeHandler, sim::Cluster};
+
+    fn gossip_msg(src: &str, msg_id: MsgId, state: &[usize]) -> NodeMessage {
+        let mut fields = Map::new();
+        fields.insert("state".to_string(), serde_json::json!(state));
+        let mut msg = NodeMessage::new(src.to_string(), "n1".to_string(), Body::Custom(CustomBody { kind: "gossip".to_string(), fields }));
+        msg.header.msg_id = Some(msg_id);
+        msg
+    }
+
+    #[test]
+    fn incoming_gossip_is_merged_and_known_by_its_sender() {
+        let ctx = Context::detached("n1", vec!["n1".to_string(), "n2".to_string()]);
+        let mut gossip = Gossip::<HashSet<usize>>::default();
+        gossip.merge(&HashSet::from([1]));
+
+        assert!(gossip.handle_msg(&ctx, &gossip_msg("n2", 1, &[2, 3])));
+        assert_eq!(gossip.state(), &HashSet::from([1, 2, 3]));
+        assert_eq!(gossip.known_by("n2"), Some(&HashSet::from([2, 3])));
+        assert_eq!(gossip.state().delta(gossip.known_by("n2").unwrap()), Some(HashSet::from([1])));
+
+        let other = NodeMessage::new("c1".to_string(), "n1".to_string(), Body::Read { key: None });
+        assert!(!gossip.handle_msg(&ctx, &other));
+    }
+
+    /// merges a client's `broadcast` into its gossip, and keeps a copy of its state for the test to inspect.
+    struct GossipNode {
+        gossip: Gossip<HashSet<usize>>,
+        states: Arc<Mutex<HashMap<NodeId, HashSet<usize>>>>,
+    }
+
+    impl NodeHandler for GossipNode {
+        fn handle_msg(&mut self, ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+            if let Body::Broadcast { message } = msg.body {
+                self.gossip.merge(&HashSet::from([message]));
+            } else {
+                self.gossip.handle_msg(ctx, &msg);
+            }
+            self.states.lock().unwrap().insert(ctx.node_id().clone(), self.gossip.state().clone());
+            None
+        }
+
+        fn handle_interval(&mut self, ctx: &Context, tag: Tag, _elapsed: Duration) -> Option<Vec<NodeMessage>> {
+            self.gossip.handle_interval(ctx, &tag);
+            None
+        }
+    }
+
+    #[test]
+    fn converges_with_partial_fanout_and_loss() {
+        let states = Arc::new(Mutex::new(HashMap::new()));
+        let mut seed = 0;
+        let mut cluster = Cluster::new(5, 3, |_| {
+                seed += 1;
+                Box::new(GossipNode { gossip: Gossip::new("gossip").with_fanout(1).with_seed(seed), states: states.clone() })
+            })
+            .latency(Duration::from_millis(1), Duration::from_millis(20))
+            .loss(0.3);
+        cluster.register_interval("gossip".to_string(), Duration::from_millis(100));
+
+        for (i, node_id) in cluster.node_ids().to_vec().iter().enumerate() {
+            cluster.client_request("c1", node_id, Body::Broadcast { message: i });
+        }
+        cluster.run_for(Duration::from_secs(10));
+
+        let states = states.lock().unwrap();
+        assert_eq!(states.len(), 5);
+        for (node_id, state) in states.iter() {
+            assert_eq!(state, &(0..5).collect::<HashSet<_>>(), "{} hasn't converged", node_id);
+        }
+    }
+}
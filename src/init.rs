@@ -1,5 +1,4 @@
 use serde::{Serialize, Deserialize};
-use std::{io::Write, ops::Add};
 
 use crate::data_models::*;
 
@@ -10,71 +9,51 @@ use crate::data_models::*;
 // this will hide these details from any specific 'node' implementation, 
 // as all nodes should utilize the 'NodeRunner'
 //
-
-#[derive(Deserialize, Serialize, Debug)]
-pub(crate) struct InitMessage {
-    pub(crate) src: NodeId,
-    pub(crate) dest: NodeId,
-    pub(crate) body: InitBody,
-}
+// `init` isn't one of `Body`'s variants, so it arrives as a `Body::Custom` 
+// (with its `msg_id` in the message's `Header`) and is parsed from there.
+//
 
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub(crate) enum InitBody {
     Init{
-        msg_id:   MsgId,
         node_id:  NodeId,
         node_ids: Vec<NodeId>,
     },
-    InitOk{
-        in_reply_to:   MsgId,
-    },
+    InitOk,
 }
 
-// ------------------------------------------------------------------------------------
-// private handler for the common 'init' work.
-//
-
-/// Handles the common 'init' workload via stdin/stdout.
-/// Returns the `node_id`
-pub(crate) fn handle_init() -> InitBody {
-
-    let mut input = std::io::stdin().lines();
-    let line = input.next()
-        .expect("input should have data")
-        .expect("input should be a valid string");
-    drop(input);
-
-    let msg = serde_json::from_str::<InitMessage>(line.as_str())
-        .expect("line should be a valid init message");
-    eprintln!("received init: \n{:?}", msg);
+impl InitBody {
+    /// returns the init details, if `msg` is an `init` message.
+    pub(crate) fn from_msg(msg: &NodeMessage) -> Option<(NodeId, Vec<NodeId>)> {
+        let Body::Custom(custom) = &msg.body else { return None };
+        if custom.kind != "init" { return None; }
+
+        match custom.parse::<InitBody>() {
+            Ok(InitBody::Init { node_id, node_ids }) => Some((node_id, node_ids)),
+            _ => None,
+        }
+    }
+
+    /// the `init_ok` reply to `msg`.
+    pub(crate) fn reply_to(msg: &NodeMessage) -> NodeMessage {
+        msg.reply(Body::custom(&InitBody::InitOk).expect("init_ok should serialize"))
+    }
+}
 
-    let response: InitMessage;
-    let id: String;
-    match &msg.body {
-        InitBody::Init { msg_id, node_id, node_ids: _ } => {
-            id = node_id.clone();
-            response = InitMessage {
-                src:    id.clone(),
-                dest:   msg.src.clone(),
-                body:   InitBody::InitOk { in_reply_to: msg_id.clone() },
-            }
-        },
-        InitBody::InitOk { in_reply_to: _ } => unreachable!("should not be receiving init_ok msg as a node"),
-    };
 
-    
-    eprintln!("prepared init response: \n{:?}", response);
-    let mut reply = serde_json::to_string(&response)
-        .expect("init_ok response should serialize");
-    reply = reply.add("\n");
+#[cfg(test)]
+mod init_tests {
+    use super::*;
 
-    let mut output = std::io::stdout().lock();
-    output.write_all(reply.as_bytes())
-        .expect("stdout should accept data");
-    output.flush()
-        .expect("stdout should flush data");
-    drop(output);
+    #[test]
+    fn init_round_trips_through_a_custom_body() {
+        let msg: NodeMessage = serde_json::from_str(r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#).unwrap();
+        let (node_id, node_ids) = InitBody::from_msg(&msg).expect("should be an init message");
+        assert_eq!(node_id, "n1");
+        assert_eq!(node_ids, vec!["n1", "n2"]);
 
-    msg.body
-}
\ No newline at end of file
+        let reply = serde_json::to_value(InitBody::reply_to(&msg)).unwrap();
+        assert_eq!(reply, serde_json::json!({"src": "n1", "dest": "c0", "body": {"type": "init_ok", "in_reply_to": 1}}));
+    }
+}
@@ -1,29 +1,31 @@
-use crate::data_models::NodeMessage;
+use crate::{data_models::*, transport::Input};
 
-use std::{io::{self, Write}, thread::{self, JoinHandle}, ops::Add};
-use tokio::sync::{oneshot, mpsc};
+use std::{io, thread};
+use tracing::{debug, trace, warn};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter}, select, sync::{oneshot, mpsc}, task::JoinHandle};
 
 
-pub(crate) struct StdinSource {
-    msg_rx: mpsc::Receiver<NodeMessage>,
+/// Reads incoming messages, one JSON object per line.
+pub(crate) struct LineSource {
+    msg_rx: mpsc::Receiver<Input>,
 }
 
-impl StdinSource {
-    pub fn new() -> Self {
-        #[allow(unused_mut)]
-        let (tx, mut rx) = mpsc::channel(100);
+impl LineSource {
+    /// reads from stdin.
+    ///
+    /// (on a plain thread rather than `tokio::io::stdin()`, which would keep the runtime from
+    /// shutting down while it's blocked on a read)
+    pub fn stdin() -> Self {
+        let (tx, rx) = mpsc::channel(100);
 
         thread::spawn(move || {
             let mut input = io::stdin().lines();
-            eprintln!("setting up StdinSource");
+            debug!("setting up LineSource");
             while let Some(Ok(line)) = input.next() {
-                let next_msg = serde_json::from_str::<NodeMessage>(line.as_str()).expect("should deserialize to a NodeMessage");
-                eprintln!("received:  {:?}", next_msg);
-
-                let _ = tx.blocking_send(next_msg).expect("should send NodeMessage via channel");
+                if tx.blocking_send(parse_line(&line)).is_err() { break; }
             }
 
-            eprintln!("cleaning up StdinSource");
+            debug!("cleaning up LineSource");
         });
 
         Self {
@@ -31,94 +33,187 @@ impl StdinSource {
         }
     }
 
-    pub async fn next_msg(&mut self) -> NodeMessage {
-        self.msg_rx.recv().await.expect("should receive NodeMessage via channel")
+    /// reads from `input` (ie one half of a socket).
+    pub fn with_reader<R: AsyncRead + Unpin + Send + 'static>(input: R) -> Self {
+        let (tx, rx) = mpsc::channel(100);
+
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(input).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if tx.send(parse_line(&line)).await.is_err() { break; }
+            }
+        });
+
+        Self {
+            msg_rx: rx,
+        }
+    }
+
+    /// the next line read, or `None` once the input has been closed.
+    pub async fn next_msg(&mut self) -> Option<Input> {
+        self.msg_rx.recv().await
     }
 }
 
-impl Default for StdinSource {
-    fn default() -> Self { Self::new() }
+fn parse_line(line: &str) -> Input {
+    match serde_json::from_str::<NodeMessage>(line) {
+        Ok(msg) => {
+            trace!("received: {}", line);
+            Input::Msg(msg)
+        },
+        Err(err) => {
+            warn!(error = %err, "received malformed line: {}", line);
+            Input::Malformed { raw: serde_json::from_str(line).ok(), error: err.to_string() }
+        },
+    }
 }
 
 
 
 
-pub(crate) struct StdoutSink {
-    msg_tx: mpsc::Sender<NodeMessage>,
-    cancel_tx: Option<oneshot::Sender<()>>,
+/// most messages written between flushes.
+const MAX_BATCH: usize = 64;
+
+/// Writes outgoing messages, one JSON object per line.
+///
+/// The writer is a task that waits on the channel, so messages go out as soon as they're queued.
+/// Under load, every message that's already waiting (up to `MAX_BATCH`) is written before a single flush.
+pub(crate) struct LineSink {
+    msg_tx: mpsc::UnboundedSender<NodeMessage>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
     writer_handle: Option<JoinHandle<()>>,
 }
 
-impl StdoutSink {
-    pub fn new() -> Self {        
-        let (msg_tx, mut msg_rx) = mpsc::channel(100);
-        let (cancel_tx, mut cancel_rx) = oneshot::channel();
-
-
-        let handle =thread::spawn(move || {
-            let mut output = io::stdout().lock();
-            eprintln!("setting up StdoutSink");
-
-            loop {
-                match cancel_rx.try_recv() {
-                    Err(oneshot::error::TryRecvError::Empty) => (),
-                    _ => break,
-                }
-                
-                match msg_rx.try_recv() {
-                    Ok(msg) => {
-                        eprintln!("sending: {:?}", msg);
-                        let mut data = serde_json::to_string(&msg).expect("message should serialize");
-                        data = data.add("\n");
-                        output.write_all(data.as_bytes()).expect("stdout should accept data");
-                        output.flush().expect("stdout should flush");
-                        continue
-                    },
-                    Err(mpsc::error::TryRecvError::Empty) => (),
-                    Err(mpsc::error::TryRecvError::Disconnected) => break,
-                }
-                
-                // lets not spin at 100% cpu on this thread...  maybe
-                thread::sleep(std::time::Duration::from_millis(1));
-            }
+impl LineSink {
+    /// writes to stdout.
+    pub fn stdout() -> Self {
+        Self::with_writer(tokio::io::stdout())
+    }
 
-            eprintln!("cleaning up StdoutSink");
+    /// writes to `output` (ie one half of a socket).
+    pub fn with_writer<W: AsyncWrite + Unpin + Send + 'static>(output: W) -> Self {
+        let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
-            cancel_rx.close();
-            msg_rx.close();
-
-            while let Some(msg) = msg_rx.blocking_recv() {
-                let data = serde_json::to_string(&msg).expect("message should serialize");
-                output.write_all(data.as_bytes()).expect("should be able to write to stdout");
-            }
-        });
+        let handle = tokio::spawn(write_msgs(output, msg_rx, shutdown_rx));
 
         Self {
             msg_tx,
-            cancel_tx: Some(cancel_tx),
+            shutdown_tx: Some(shutdown_tx),
             writer_handle: Some(handle),
         }
     }
 
-    pub async fn send_msg(&self, msg: NodeMessage) {
-        self.msg_tx.send(msg).await
-            .expect("should send NodeMessage via channel")
+    /// a handle for queueing messages on this sink.
+    pub fn sender(&self) -> mpsc::UnboundedSender<NodeMessage> {
+        self.msg_tx.clone()
+    }
+
+    /// stops the writer, once every message queued so far has been written and flushed.
+    ///
+    /// (messages queued after this are dropped)
+    pub async fn shutdown(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+
+        if let Some(handle) = self.writer_handle.take() {
+            let _ = handle.await;
+        }
+    }
+}
+
+async fn write_msgs<W: AsyncWrite + Unpin>(output: W, mut msg_rx: mpsc::UnboundedReceiver<NodeMessage>, mut shutdown_rx: oneshot::Receiver<()>) {
+    let mut output = BufWriter::new(output);
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    debug!("setting up LineSink");
+
+    loop {
+        select! {
+            received = msg_rx.recv_many(&mut batch, MAX_BATCH) => {
+                if received == 0 { break; }
+                if let Err(err) = write_batch(&mut output, &mut batch).await {
+                    warn!(error = %err, "failed to write output");
+                    return;
+                }
+            },
+            // (also fires if the sink was dropped without a `shutdown()`)
+            _ = &mut shutdown_rx => break,
+        }
     }
 
+    debug!("cleaning up LineSink");
+
+    // write out anything that was queued before we stopped
+    msg_rx.close();
+    while msg_rx.recv_many(&mut batch, MAX_BATCH).await > 0 {
+        if let Err(err) = write_batch(&mut output, &mut batch).await {
+            warn!(error = %err, "failed to write output");
+            return;
+        }
+    }
 }
 
-impl Default for StdoutSink {
-    fn default() -> Self { Self::new() }
+async fn write_batch<W: AsyncWrite + Unpin>(output: &mut BufWriter<W>, batch: &mut Vec<NodeMessage>) -> io::Result<()> {
+    for msg in batch.drain(..) {
+        let mut data = serde_json::to_vec(&msg).expect("message should serialize");
+        trace!("sending: {}", String::from_utf8_lossy(&data));
+        data.push(b'\n');
+        output.write_all(&data).await?;
+    }
+    output.flush().await
 }
 
-impl Drop for StdoutSink {
-    fn drop(&mut self) {
-        if let Some(tx) = self.cancel_tx.take() {
-            let _ = tx.send(());
+
+#[cfg(test)]
+mod io_tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn malformed_lines_keep_their_json() {
+        let Input::Malformed { raw, error: _ } = parse_line(r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":3}}"#) else {
+            panic!("an 'add' without a 'delta' should be malformed");
+        };
+        assert_eq!(raw.unwrap().pointer("/body/msg_id"), Some(&serde_json::json!(3)));
+
+        assert!(matches!(parse_line("not json"), Input::Malformed { raw: None, .. }));
+        assert!(matches!(parse_line(r#"{"src":"c1","dest":"n1","body":{"type":"custom_thing"}}"#), Input::Msg(_)));
+    }
+
+    #[tokio::test]
+    async fn drains_queued_messages_on_shutdown() {
+        let (output, mut input) = tokio::io::duplex(64 * 1024);
+        let mut sink = LineSink::with_writer(output);
+
+        let tx = sink.sender();
+        for i in 0..(MAX_BATCH * 2 + 1) {
+            tx.send(NodeMessage::new("n1".to_string(), "c1".to_string(), Body::Broadcast { message: i })).unwrap();
         }
+        sink.shutdown().await;
 
-        if let Some(handle) = self.writer_handle.take() {
-            let _ = handle.join();
+        let mut written = String::new();
+        input.read_to_string(&mut written).await.unwrap();
+
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), MAX_BATCH * 2 + 1);
+        assert!(written.ends_with('\n'));
+        for (i, line) in lines.iter().enumerate() {
+            let msg: NodeMessage = serde_json::from_str(line).unwrap();
+            assert!(matches!(msg.body, Body::Broadcast { message } if message == i));
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn reads_lines_until_closed() {
+        let (mut output, input) = tokio::io::duplex(1024);
+        let mut source = LineSource::with_reader(input);
+
+        output.write_all(b"{\"src\":\"c1\",\"dest\":\"n1\",\"body\":{\"type\":\"echo\",\"echo\":\"hi\",\"msg_id\":1}}\nnot json\n").await.unwrap();
+        drop(output);
+
+        assert!(matches!(source.next_msg().await, Some(Input::Msg(NodeMessage { body: Body::Echo { .. }, .. }))));
+        assert!(matches!(source.next_msg().await, Some(Input::Malformed { raw: None, .. })));
+        assert!(source.next_msg().await.is_none());
+    }
+}
@@ -1,87 +1,235 @@
+pub mod check;
+pub mod context;
+pub mod crdt;
 pub mod data_models;
+pub mod gossip;
 pub mod io;
+pub mod logging;
+pub mod reliable;
+pub mod rpc;
+pub mod services;
+pub mod sim;
+pub mod topology;
+pub mod transport;
 mod init;
 
 use anyhow::{Result, anyhow};
+use context::{Clock, Context, Timer};
 use init::InitBody;
-use io::{StdinSource, StdoutSink};
+use rpc::{RpcClient, RpcError, PendingReply};
 use tokio::{time, select, sync::mpsc};
-use std::{collections::HashMap, cell::{RefCell, Cell}, rc::Rc, time::{Duration, Instant}};
+use tracing::{debug, info, info_span, warn, Instrument};
+use transport::{Input, StdioTransport, Transport};
+use std::{collections::HashMap, future::Future, pin::Pin, sync::{Arc, Mutex}, time::{Duration, Instant}};
 
 use crate::data_models::*;
 
+/// how often requests that never received a reply are cleaned up.
+const RPC_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
 
 type Tag = String;
 
-pub trait NodeHandler {
-    /// This is called once when this instance is passed to `NodeRunner`'s `assign_handler()` method.
-    fn init(&mut self, node_id: NodeId, node_ids:Vec<NodeId>);
+/// Handlers are owned by the `NodeRunner` they're registered with, and must be `Send` so the
+/// runner can be moved between threads (ie spawned on a multi-threaded tokio runtime).
+pub trait NodeHandler: Send {
+    /// This is called once the node has received its `init` message, before any other callback.
+    /// 
+    /// (the same details are available from the `Context` passed to every other callback)
+    fn init(&mut self, _node_id: NodeId, _node_ids:Vec<NodeId>) {}
 
     /// This is called any time a message is received for the 'NodeType' passed to the `assign_handler()` method.
-    fn handle_msg(&mut self, msg: NodeMessage) -> Option<Vec<NodeMessage>>;
+    fn handle_msg(&mut self, ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>>;
 
-    /// This is called anytime a registered interval is triggered.  
+    /// This is called anytime a registered interval (or a timer from `Context::schedule_after()`) is triggered.  
     ///   -- `tag` is the tag associated with the interval when the interval was registered.
-    ///   -- `elapsed` is the duration elapsed since the `NodeRunner` started `run_node()`
-    fn handle_interval(&mut self, _tag: Tag, _elapsed: Duration) -> Option<Vec<NodeMessage>> {
+    ///   -- `elapsed` is the duration elapsed since the `NodeRunner` started
+    fn handle_interval(&mut self, _ctx: &Context, _tag: Tag, _elapsed: Duration) -> Option<Vec<NodeMessage>> {
         None
     }
+
+    /// This is called once when the runner shuts down (input closed, or SIGINT), before any
+    /// remaining output is flushed.  Anything sent through `ctx` here is still written out.
+    fn on_shutdown(&mut self, _ctx: &Context) {}
 }
 
+/// The async counterpart to `NodeHandler`, for handlers that need to await a KV read,
+/// a peer RPC, or a timer while handling a message.
+///
+/// The runner spawns a task for every message (and interval), so a slow message never holds up
+/// the ones behind it.  Handlers are shared between those tasks, so any state they keep needs
+/// interior mutability (ie a `Mutex`).
+///
+/// The `Context` can be used to send messages and await replies while the task runs, and any
+/// messages the handler returns are sent once it completes.
+pub trait AsyncNodeHandler: Send + Sync + 'static {
+    /// This is called once the node has received its `init` message, before any other callback.
+    fn init(&mut self, _node_id: NodeId, _node_ids:Vec<NodeId>) {}
 
-#[derive(Default)]
-pub struct NodeRunner<'a> {
-    
-    /// NodeId of this process
-    node_id: NodeId,
-    
-    /// NodeId's of all the nodes in our 'network'
-    node_ids: Vec<NodeId>,
+    /// This is called (in its own task) any time a message is received for the 'NodeType's it was registered for.
+    fn handle_msg(&self, ctx: Context, msg: NodeMessage) -> impl Future<Output = Option<Vec<NodeMessage>>> + Send;
+
+    /// This is called (in its own task) anytime a registered interval (or timer) is triggered.
+    fn handle_interval(&self, _ctx: Context, _tag: Tag, _elapsed: Duration) -> impl Future<Output = Option<Vec<NodeMessage>>> + Send {
+        async { None }
+    }
+
+    /// This is called (and awaited) once when the runner shuts down, before any remaining output is flushed.
+    fn on_shutdown(&self, _ctx: Context) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Option<Vec<NodeMessage>>> + Send>>;
+
+/// an `AsyncNodeHandler` with its futures boxed, so handlers of different types can share a map.
+trait DynAsyncHandler: Send + Sync {
+    fn init(&mut self, node_id: NodeId, node_ids: Vec<NodeId>);
+    fn handle_msg(self: Arc<Self>, ctx: Context, msg: NodeMessage) -> HandlerFuture;
+    fn handle_interval(self: Arc<Self>, ctx: Context, tag: Tag, elapsed: Duration) -> HandlerFuture;
+    fn on_shutdown(self: Arc<Self>, ctx: Context) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+impl<T: AsyncNodeHandler> DynAsyncHandler for T {
+    fn init(&mut self, node_id: NodeId, node_ids: Vec<NodeId>) {
+        AsyncNodeHandler::init(self, node_id, node_ids)
+    }
+
+    fn handle_msg(self: Arc<Self>, ctx: Context, msg: NodeMessage) -> HandlerFuture {
+        Box::pin(async move { AsyncNodeHandler::handle_msg(&*self, ctx, msg).await })
+    }
+
+    fn handle_interval(self: Arc<Self>, ctx: Context, tag: Tag, elapsed: Duration) -> HandlerFuture {
+        Box::pin(async move { AsyncNodeHandler::handle_interval(&*self, ctx, tag, elapsed).await })
+    }
+
+    fn on_shutdown(self: Arc<Self>, ctx: Context) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async move { AsyncNodeHandler::on_shutdown(&*self, ctx).await })
+    }
+}
+
+/// handles any message that no registered handler accepts, as raw json.
+type CatchAllHandler = Box<dyn FnMut(&Context, serde_json::Value) -> Option<Vec<NodeMessage>> + Send>;
+
+enum Handler {
+    Sync(Arc<Mutex<dyn NodeHandler>>),
+    Async(Arc<dyn DynAsyncHandler>),
+}
+
+
+/// Runs a node's handlers over a `Transport` (stdin / stdout, unless another is given via `with_transport()`).
+pub struct NodeRunner<T: Transport = StdioTransport> {
     
-    // tracks the current 'next message id'
-    next_msg_id: Cell<MsgId>,
+    // hands out `msg_id`s and tracks requests waiting on a reply
+    client: RpcClient,
+    // handed to every handler callback
+    ctx: Context,
+    timer_rx: mpsc::UnboundedReceiver<Timer>,
     
     running: bool,
-    start_time: Option<Instant>,
+    // set once `init` has been handled; until then, incoming lines are queued in `early`
+    initialized: bool,
+    early: Vec<Input>,
 
-    handlers: HashMap<Workload, Rc<RefCell<&'a mut dyn NodeHandler>>>,
+    handlers: Vec<Handler>,
+    // message type -> index into `handlers`
+    routes: HashMap<Workload, usize>,
+    catch_all: Option<CatchAllHandler>,
+    // incoming lines that weren't valid messages
+    parse_failures: usize,
     intervals: HashMap<Tag, Duration>,
 
-    msg_source: StdinSource,
-    msg_sink: StdoutSink,
+    // messages are queued via `client`, and read back out by the transport
+    transport: T,
+}
+
+impl Default for NodeRunner {
+    fn default() -> Self { Self::new() }
 }
 
-impl<'a> NodeRunner<'a> {
+impl NodeRunner {
 
-    /// create a new runner instance that initializes with the 'node id' for this process
+    /// create a new runner instance that talks to Maelstrom over stdin / stdout.
     /// 
-    /// (ie automatically handles the one-time 'init' message)
+    /// (the one-time 'init' message is handled automatically, once `run_node()` is called)
+    /// 
+    /// This also sets up logging to stderr.  (see `logging::init()`)
     pub fn new() -> Self {
-        if let InitBody::Init { msg_id: _, node_id, node_ids } = init::handle_init() {
-            return NodeRunner {
-                node_id,
-                node_ids,
-                ..Default::default()
-            }
+        logging::init();
+        Self::with_transport(StdioTransport::new())
+    }
+}
+
+impl<T: Transport> NodeRunner<T> {
+
+    /// create a new runner instance that sends and receives messages over `transport`.
+    pub fn with_transport(transport: T) -> Self {
+        let client = RpcClient::unassigned(transport.sender());
+        let (timer_tx, timer_rx) = mpsc::unbounded_channel();
+        let ctx = Context::unassigned(client.clone(), Clock::Real(Instant::now()), timer_tx);
+
+        NodeRunner {
+            client,
+            ctx,
+            timer_rx,
+            running: false,
+            initialized: false,
+            early: Vec::new(),
+            handlers: Vec::new(),
+            routes: HashMap::new(),
+            catch_all: None,
+            parse_failures: 0,
+            intervals: HashMap::new(),
+            transport,
         }
-        unreachable!("we must receive an Init variant");
     }
 
     /// this should be called after `new()` and before `run_node()`.
     /// 
     /// This sets up a mapping to message type -> handlers.
-    pub fn register_handler<T: NodeHandler>(&mut self, handler: &'a mut T, for_types: &[NodeType]) -> bool {
+    /// The runner takes ownership of the handler.  (use an `Arc<Mutex<_>>` inside the handler
+    /// to share any state that needs to be inspected from outside the runner)
+    pub fn register_handler<H: NodeHandler + 'static>(&mut self, handler: H, for_types: &[NodeType]) -> bool {
         if self.running { return false; }
-        
-        handler.init(self.node_id.clone(), self.node_ids.clone());
 
-        let handler_ref = Rc::new(RefCell::new(handler as &mut dyn NodeHandler));
+        self.add_handler(Handler::Sync(Arc::new(Mutex::new(handler))), for_types);
+        true
+    }
+
+    /// the same as `register_handler()`, for an `AsyncNodeHandler`.
+    pub fn register_async_handler<H: AsyncNodeHandler>(&mut self, handler: H, for_types: &[NodeType]) -> bool {
+        if self.running { return false; }
+
+        self.add_handler(Handler::Async(Arc::new(handler)), for_types);
+        true
+    }
+
+    fn add_handler(&mut self, handler: Handler, for_types: &[NodeType]) {
+        self.handlers.push(handler);
         for node_type in for_types {
-            self.handlers.insert(node_type.to_string(), handler_ref.clone());
+            self.routes.insert(node_type.to_string(), self.handlers.len() - 1);
         }
+    }
+
+    /// sets a handler for every message that no registered handler accepts (ie a `type` that
+    /// `Body` doesn't know about), which is passed the message as raw json.
+    /// 
+    /// Without one, those messages are answered with a `not-supported` error.
+    pub fn register_catch_all<F>(&mut self, handler: F) -> bool
+    where
+        F: FnMut(&Context, serde_json::Value) -> Option<Vec<NodeMessage>> + Send + 'static,
+    {
+        if self.running { return false; }
+
+        self.catch_all = Some(Box::new(handler));
         true
     }
 
+    /// number of incoming lines that weren't valid messages.
+    pub fn parse_failures(&self) -> usize {
+        self.parse_failures
+    }
+
     pub fn register_interval(&mut self, tag: Tag, interval: Duration) -> bool {
         if self.running { return false; }
 
@@ -89,12 +237,39 @@ impl<'a> NodeRunner<'a> {
         true
     }
 
-    /// runs the 'main loop' where stdin is read line-by-line and passed to the 'handler' set via the `assign_handler()` method
+    /// returns the context that's passed to every handler callback.
+    pub fn context(&self) -> Context {
+        self.ctx.clone()
+    }
+
+    /// returns a handle for sending messages and awaiting replies, which can be moved into spawned tasks.
+    /// 
+    /// Replies are routed to the waiting request by the 'main loop', so they only arrive while `run_node()` is running.
+    pub fn rpc_client(&self) -> RpcClient {
+        self.client.clone()
+    }
+
+    /// sends `msg` as a request, and returns a future that resolves with the matching reply.
+    /// 
+    /// The `msg_id` the request was sent with is available via `PendingReply::msg_id()`.
+    /// If no reply arrives within `timeout`, the future resolves to `RpcError::Timeout`.
+    pub async fn call(&self, msg: NodeMessage, timeout: Duration) -> PendingReply {
+        self.client.call(msg, timeout).await
+    }
+
+    /// sends `msg` as a request, and waits for the matching reply.
+    pub async fn rpc(&self, msg: NodeMessage, timeout: Duration) -> Result<NodeMessage, RpcError> {
+        self.client.rpc(msg, timeout).await
+    }
+
+    /// runs the 'main loop' where messages are read from the transport and passed to the 'handler' set via the `assign_handler()` method
+    /// 
+    /// Returns once the input is closed (or on SIGINT), after every handler's `on_shutdown()` has run and all output is flushed.
     pub async fn run_node(&mut self) -> Result<()> {
         self.running = true;
-        self.start_time = Some(Instant::now());
 
-        if self.handlers.len() == 0 { return Err(anyhow!("no handlers registered")); }
+        if self.handlers.is_empty() { return Err(anyhow!("no handlers registered")); }
+
     
         // setup any 'intervals'
         let (int_tx, mut int_rx) = mpsc::channel(10);
@@ -103,7 +278,7 @@ impl<'a> NodeRunner<'a> {
             .for_each(|(t, d)| { 
                 let tx = int_tx.clone();
                 let tag = t.clone();
-                let dur = d.clone();
+                let dur = *d;
                 tokio::task::spawn(async move { 
                     let mut interval = time::interval(dur);
                     let fut_tag = tag;
@@ -112,73 +287,356 @@ impl<'a> NodeRunner<'a> {
                     loop {
                         let loop_tag = fut_tag.clone();
                         interval.tick().await;
-                        let _ = tx.send(loop_tag).await;
+                        let _ = tx.send((loop_tag, None)).await;
                     }
                 });
             });
         
-        let (sig_tx, mut sig_rx) = mpsc::channel(10);
-        ctrlc::set_handler(move || {
-            let _ = sig_tx.blocking_send(());
-        }).expect("should set SIGINT handler");
+        // (`tokio::signal` can be awaited by any number of runners in the same process)
+        let sigint = tokio::signal::ctrl_c();
+        tokio::pin!(sigint);
+
+        let mut rpc_sweep = time::interval(RPC_SWEEP_INTERVAL);
 
 
         loop {
             select! {
-                msg = self.msg_source.next_msg() => {
-                    // eprintln!("run_node dispatching msg:  {:?}", msg
-                    if let Some(msg_type) = msg.as_node_type() {
-                        let key: Workload = msg_type.to_string();
-                        if let Some(handler_rc) = self.handlers.get(&key) {
-                            let mut handler = handler_rc.borrow_mut();
-                            
-                            if let Some(responses) = handler.handle_msg(msg) {
-                                self.send_msgs(responses).await;
-                            }
-                        } else {
-                            eprintln!("no handler for workload: {}", key);
-                        }
-                    }
+                input = self.transport.recv() => match input {
+                    Some(input) => self.receive(input),
+                    // the input was closed, so there's nothing left to do
+                    None => break,
                 },
                 t = int_rx.recv() => {
-                    if let Some(tag) = t {
-                        for (_, handler_rc) in &self.handlers  {
-                            let mut handler = handler_rc.borrow_mut();
-                            let start_time = self.start_time.unwrap();
-                            if let Some(msgs) = handler.handle_interval(tag.clone(), start_time.elapsed()) {
-                                self.send_msgs(msgs).await;
+                    // (handlers don't hear about intervals until they've been initialized)
+                    if let Some((tag, target)) = t.filter(|_| self.initialized) {
+                        let span = info_span!("interval", node_id = %self.ctx.node_id(), %tag);
+                        let _entered = span.enter();
+
+                        let elapsed = self.ctx.elapsed();
+                        // (an interval goes to every handler, a timer only to the one that scheduled it)
+                        let handlers = self.handlers.iter().enumerate()
+                            .filter(|(idx, _)| target.is_none_or(|target| target == *idx));
+                        for (idx, handler) in handlers {
+                            let ctx = self.ctx.for_handler(idx);
+                            match handler {
+                                Handler::Sync(handler_rc) => {
+                                    let msgs = handler_rc.lock().unwrap().handle_interval(&ctx, tag.clone(), elapsed);
+                                    if let Some(msgs) = msgs {
+                                        self.send_msgs(msgs);
+                                    }
+                                },
+                                Handler::Async(handler) => {
+                                    self.spawn_handler(handler.clone().handle_interval(ctx, tag.clone(), elapsed));
+                                },
                             }
                         }
                     }
                 },
-                _ = sig_rx.recv() => {
+                Some(Timer { tag, after, handler }) = self.timer_rx.recv() => {
+                    let tx = int_tx.clone();
+                    tokio::spawn(async move {
+                        time::sleep(after).await;
+                        let _ = tx.send((tag, handler)).await;
+                    });
+                },
+                _ = rpc_sweep.tick() => {
+                    self.client.expire_pending();
+                },
+                _ = &mut sigint => {
                     break
                 },
             }
         }
 
-        eprintln!("processed all messages, exiting successfully");
+        self.shutdown().await;
+
+        if !self.initialized {
+            return Err(anyhow!("input closed before the node received `init`"));
+        }
+
+        info!("processed all messages, exiting successfully");
 
         Ok(())
     }
 
 
+    /// handles a single incoming line.
+    /// 
+    /// Until `init` arrives, everything else is queued, and then handled (in order) once it has.
+    fn receive(&mut self, input: Input) {
+        let msg = match input {
+            Input::Msg(msg) => msg,
+            input @ Input::Malformed { .. } if !self.initialized => return self.early.push(input),
+            Input::Malformed { raw, error } => return self.reject_malformed(raw, error),
+        };
+
+        let span = info_span!("msg", node_id = %self.ctx.node_id(), msg_id = msg.header.msg_id, src = %msg.src, r#type = msg.body.kind());
+        let _entered = span.enter();
+
+        if InitBody::from_msg(&msg).is_some() {
+            self.handle_init(msg);
+
+            // (each queued message gets its own span, rather than nesting in init's)
+            drop(_entered);
+            for input in std::mem::take(&mut self.early) {
+                self.receive(input);
+            }
+            return;
+        }
+        if !self.initialized {
+            debug!("queued until init");
+            return self.early.push(Input::Msg(msg));
+        }
+
+        // replies to outstanding requests go to whoever is waiting on them
+        let Some(msg) = self.client.resolve(msg) else {
+            debug!("resolved a pending request");
+            return
+        };
+
+        let Some(idx) = msg.as_node_types()
+            .iter()
+            .find_map(|msg_type| self.routes.get(&msg_type.to_string()))
+            .copied()
+        else {
+            return self.reject_unhandled(msg);
+        };
+
+        let ctx = self.ctx.for_msg(&msg).for_handler(idx);
+        match &self.handlers[idx] {
+            Handler::Sync(handler_rc) => {
+                let responses = handler_rc.lock().unwrap().handle_msg(&ctx, msg);
+
+                if let Some(responses) = responses {
+                    self.send_msgs(responses);
+                }
+            },
+            Handler::Async(handler) => {
+                self.spawn_handler(handler.clone().handle_msg(ctx, msg));
+            },
+        }
+    }
+
+    /// initializes every handler, and replies `init_ok`.
+    /// 
+    /// (a repeated `init` is acknowledged again, but doesn't re-initialize anything)
+    fn handle_init(&mut self, msg: NodeMessage) {
+        if !self.initialized {
+            let (node_id, node_ids) = InitBody::from_msg(&msg).expect("should be an init message");
+            info!(node_id, ?node_ids, "received init");
+            self.init_handlers(&node_id, &node_ids);
+            self.ctx.assign(node_id, node_ids);
+        }
+        self.client.send(InitBody::reply_to(&msg));
+        self.initialized = true;
+    }
+
+    fn init_handlers(&mut self, node_id: &NodeId, node_ids: &[NodeId]) {
+        for handler in &mut self.handlers {
+            match handler {
+                Handler::Sync(handler_rc) => handler_rc.lock().unwrap().init(node_id.clone(), node_ids.to_vec()),
+                Handler::Async(handler) => {
+                    // (nothing else holds the handler until the main loop starts)
+                    Arc::get_mut(handler)
+                        .expect("async handlers aren't shared before init")
+                        .init(node_id.clone(), node_ids.to_vec())
+                },
+            }
+        }
+    }
+
+    /// gives every handler a chance to finish up, then flushes anything still waiting to be written.
+    async fn shutdown(&mut self) {
+        if !self.early.is_empty() {
+            warn!("dropping {} messages that arrived before `init`", self.early.len());
+        }
+
+        // (handlers that were never initialized have nothing to finish up)
+        for (idx, handler) in self.handlers.iter().enumerate().filter(|_| self.initialized) {
+            match handler {
+                Handler::Sync(handler_rc) => handler_rc.lock().unwrap().on_shutdown(&self.ctx.for_handler(idx)),
+                Handler::Async(handler) => handler.clone().on_shutdown(self.ctx.for_handler(idx)).await,
+            }
+        }
+
+        if self.parse_failures > 0 {
+            warn!("{} incoming lines were not valid messages", self.parse_failures);
+        }
+
+        self.transport.shutdown().await;
+    }
+
+    /// passes messages that no registered handler accepts to the catch-all handler,
+    /// or otherwise replies `not-supported` to them.
+    /// 
+    /// (unhandled replies are only logged, so two nodes can't bounce errors back and forth)
+    fn reject_unhandled(&mut self, msg: NodeMessage) {
+        if let Some(catch_all) = &mut self.catch_all {
+            let raw = serde_json::to_value(&msg).expect("message should serialize");
+            if let Some(msgs) = catch_all(&self.ctx.for_msg(&msg), raw) {
+                self.send_msgs(msgs);
+            }
+            return;
+        }
+
+        warn!("no handler for msg: {:?}", msg);
+
+        if msg.header.in_reply_to.is_some() { return; }
+
+        if let Some(error) = msg.error_reply(ErrorCode::NotSupported, "no handler registered for this message type") {
+            self.client.send(error);
+        }
+    }
+
+    /// runs an async handler's future in its own task, sending whatever it returns.
+    /// 
+    /// (the task stays in whichever span it was spawned from, ie the message's)
+    fn spawn_handler(&self, handler_fut: HandlerFuture) {
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if let Some(msgs) = handler_fut.await {
+                for msg in msgs {
+                    client.send(msg);
+                }
+            }
+        }.in_current_span());
+    }
+
+    /// replies `malformed-request` to a line that wasn't a valid message, when it has a `src` and `msg_id` to reply to.
+    fn reject_malformed(&mut self, raw: Option<serde_json::Value>, error: String) {
+        self.parse_failures += 1;
+
+        let raw = raw.unwrap_or_default();
+        let src = raw.get("src").and_then(|src| src.as_str());
+        let msg_id = raw.pointer("/body/msg_id").and_then(|msg_id| msg_id.as_u64());
+
+        if let (Some(src), Some(msg_id)) = (src, msg_id) {
+            let mut error = NodeMessage::new(self.ctx.node_id().clone(), src.to_string(), Body::Error {
+                code: ErrorCode::MalformedRequest,
+                text: error,
+            });
+            error.header.in_reply_to = Some(msg_id as MsgId);
+            self.client.send(error);
+        }
+    }
+
     /// assigns the message the next available `msg_id`
     /// then handles sending it
-    async fn send_msgs(&self, msgs: Vec<NodeMessage>) {
-        for mut msg in msgs {
-            msg.body.set_msg_id(self.get_next_msg_id());
-            self.msg_sink.send_msg(msg).await;
+    fn send_msgs(&self, msgs: Vec<NodeMessage>) {
+        for msg in msgs {
+            self.client.send(msg);
         }
     }
 
-    /// NodeHandlers should use this to generate unique msg_ids for all their outgoing messages.
-    fn get_next_msg_id(&self) -> MsgId {
-        let next_id = self.next_msg_id.get().wrapping_add(1);
-        self.next_msg_id.set(next_id);
+}
+
+
+#[cfg(test)]
+mod lib_tests {
+    use super::*;
 
-        next_id
+    fn assert_send<T: Send>(_: &T) {}
+
+    /// only needs to compile: a runner (and its main loop) can be moved onto another thread.
+    #[allow(dead_code)]
+    fn runner_is_send<T: Transport>(mut runner: NodeRunner<T>) {
+        assert_send(&runner.run_node());
+        assert_send(&runner);
     }
-    
-}
 
+    struct EchoNode;
+
+    impl NodeHandler for EchoNode {
+        fn handle_msg(&mut self, ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+            assert_eq!(ctx.msg_id(), msg.header.msg_id);
+            match &msg.body {
+                Body::Echo { echo } => Some(vec![msg.reply(Body::EchoOk { echo: echo.clone() })]),
+                _ => None,
+            }
+        }
+    }
+
+    fn client_msg(raw: &str) -> NodeMessage {
+        serde_json::from_str(raw).unwrap()
+    }
+
+    #[tokio::test]
+    async fn runs_over_a_channel_transport() {
+        let (transport, mut handle) = transport::ChannelTransport::new();
+        let mut runner = NodeRunner::with_transport(transport);
+        runner.register_handler(EchoNode, &[NodeType::Echo]);
+        let ctx = runner.context();
+        let running = tokio::spawn(async move { runner.run_node().await });
+
+        // anything that arrives before `init` is held until it's been handled
+        handle.send(client_msg(r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"early","msg_id":1}}"#));
+        handle.send(client_msg(r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#));
+
+        let init_ok = handle.recv().await.unwrap();
+        assert!(matches!(&init_ok.body, Body::Custom(custom) if custom.kind == "init_ok"));
+        assert_eq!((init_ok.src.as_str(), init_ok.dest.as_str(), init_ok.header.in_reply_to), ("n1", "c0", Some(1)));
+
+        let early = handle.recv().await.unwrap();
+        assert!(matches!(&early.body, Body::EchoOk { echo } if echo == "early"));
+        assert_eq!((early.src.as_str(), early.header.in_reply_to), ("n1", Some(1)));
+
+        handle.send(client_msg(r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":2}}"#));
+        let reply = handle.recv().await.unwrap();
+        assert!(matches!(&reply.body, Body::EchoOk { echo } if echo == "hi"));
+        assert_eq!(reply.header.in_reply_to, Some(2));
+        assert_eq!(ctx.node_ids(), ["n1", "n2"]);
+        assert_eq!(ctx.msg_id(), None, "only the handler's copy of the context has a msg_id");
+
+        handle.close();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn closing_before_init_is_an_error() {
+        let (transport, mut handle) = transport::ChannelTransport::new();
+        let mut runner = NodeRunner::with_transport(transport);
+        runner.register_handler(EchoNode, &[NodeType::Echo]);
+
+        handle.send(client_msg(r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"early","msg_id":1}}"#));
+        handle.close();
+
+        assert!(runner.run_node().await.is_err());
+        drop(runner);
+        assert!(handle.recv().await.is_none(), "messages queued before init should not be answered");
+    }
+
+    /// schedules a timer on `echo`, and reports every interval it hears about to `c1`.
+    struct TimerNode(&'static str);
+
+    impl NodeHandler for TimerNode {
+        fn handle_msg(&mut self, ctx: &Context, _msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+            ctx.schedule_after("ping".to_string(), Duration::from_millis(1));
+            None
+        }
+
+        fn handle_interval(&mut self, ctx: &Context, tag: Tag, _elapsed: Duration) -> Option<Vec<NodeMessage>> {
+            let echo = format!("{} {}", self.0, tag);
+            Some(vec![NodeMessage::new(ctx.node_id().clone(), "c1".to_string(), Body::EchoOk { echo })])
+        }
+    }
+
+    #[tokio::test]
+    async fn timers_only_fire_on_the_handler_that_scheduled_them() {
+        let (transport, mut handle) = transport::ChannelTransport::new();
+        let mut runner = NodeRunner::with_transport(transport);
+        runner.register_handler(TimerNode("a"), &[NodeType::Echo]);
+        runner.register_handler(TimerNode("b"), &[NodeType::Generate]);
+        let running = tokio::spawn(async move { runner.run_node().await });
+
+        handle.send(client_msg(r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#));
+        handle.send(client_msg(r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":1}}"#));
+        handle.recv().await.unwrap();
+
+        let fired = handle.recv().await.unwrap();
+        assert!(matches!(&fired.body, Body::EchoOk { echo } if echo == "a ping"), "{:?}", fired.body);
+
+        handle.close();
+        running.await.unwrap().unwrap();
+        assert!(handle.recv().await.is_none(), "the timer shouldn't have reached the other handler");
+    }
+}
@@ -0,0 +1,25 @@
+use tracing_subscriber::EnvFilter;
+
+
+/// env var the log filter is read from, in `tracing_subscriber::EnvFilter` syntax.
+///
+/// ie `CHAOS_LOG=debug`, or `CHAOS_LOG=info,chaos::io=trace` to also dump every raw message sent and received.
+pub const LOG_ENV: &str = "CHAOS_LOG";
+
+/// filter used when `CHAOS_LOG` isn't set.  (raw message dumps are logged at `trace`, so they're off)
+const DEFAULT_FILTER: &str = "info";
+
+/// installs a global `tracing` subscriber that writes to stderr, filtered by `CHAOS_LOG`.
+///
+/// This is called by `NodeRunner::new()`, and does nothing if a subscriber has already been installed.
+/// (so a binary can install its own first)
+pub fn init() {
+    let filter = EnvFilter::try_from_env(LOG_ENV)
+        .unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .try_init();
+}
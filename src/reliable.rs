@@ -0,0 +1,273 @@
+use std::{collections::{BTreeMap, HashMap}, time::Duration};
+
+use crate::{Tag, context::Context, data_models::*};
+
+
+/// the timer tag used by `Reliable::default()`.
+pub const DEFAULT_RETRY_TAG: &str = "reliable-retry";
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(4);
+
+/// At-least-once delivery for messages sent to other nodes.
+///
+/// Each message sent through `send()` is kept until a reply to it arrives (passed in via `ack()`),
+/// and is retransmitted on an exponential backoff until then.  Retries are driven by the runner's
+/// timers: `send()` schedules one with `Context::schedule_after()`, and the handler passes every
+/// `handle_interval()` callback on to `handle_interval()` here.
+///
+/// A retransmission goes out with a new `msg_id`, so a reply to any attempt acknowledges the message.
+/// (receivers may see a message more than once, so handling it must be idempotent)
+#[derive(Debug)]
+pub struct Reliable {
+    tag: Tag,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+
+    next_key: u64,
+    // (ordered, so retries go out in the same order every run)
+    pending: BTreeMap<u64, Pending>,
+    // (dest, msg_id of any attempt) -> key into `pending`
+    attempts: HashMap<(NodeId, MsgId), u64>,
+    // dest -> number of entries in `pending`
+    outstanding: HashMap<NodeId, usize>,
+    // when the retry timer we've already scheduled fires
+    timer_at: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    dest: NodeId,
+    body: Body,
+    msg_ids: Vec<MsgId>,
+    retries: u32,
+    retry_at: Duration,
+}
+
+impl Default for Reliable {
+    fn default() -> Self { Self::new(DEFAULT_RETRY_TAG.to_string()) }
+}
+
+impl Reliable {
+    /// `tag` is the timer tag retries are scheduled under, and must not clash with any other interval or timer.
+    pub fn new(tag: Tag) -> Self {
+        Self {
+            tag,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            next_key: 0,
+            pending: BTreeMap::new(),
+            attempts: HashMap::new(),
+            outstanding: HashMap::new(),
+            timer_at: None,
+        }
+    }
+
+    /// waits `initial` before the first retry, doubling every retry after that up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// the timer tag retries are scheduled under.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// sends `body` to `dest`, and keeps retrying until it's acknowledged.
+    ///
+    /// Returns the `msg_id` of the first attempt.
+    pub fn send(&mut self, ctx: &Context, dest: &str, body: Body) -> MsgId {
+        let msg_id = ctx.send(dest, body.clone());
+        let retry_at = ctx.elapsed() + self.initial_backoff;
+
+        let key = self.next_key;
+        self.next_key += 1;
+        self.pending.insert(key, Pending { dest: dest.to_string(), body, msg_ids: vec![msg_id], retries: 0, retry_at });
+        self.attempts.insert((dest.to_string(), msg_id), key);
+        *self.outstanding.entry(dest.to_string()).or_default() += 1;
+
+        self.schedule_retry(ctx, retry_at);
+        msg_id
+    }
+
+    /// stops retrying whichever message `msg` is a reply to.
+    ///
+    /// Returns `false` if `msg` isn't a reply to anything still outstanding (ie a duplicate reply).
+    pub fn ack(&mut self, msg: &NodeMessage) -> bool {
+        let Some(in_reply_to) = msg.header.in_reply_to else { return false };
+        let Some(key) = self.attempts.remove(&(msg.src.clone(), in_reply_to)) else { return false };
+        let Some(pending) = self.pending.remove(&key) else { return false };
+
+        for msg_id in &pending.msg_ids {
+            self.attempts.remove(&(pending.dest.clone(), *msg_id));
+        }
+        if let Some(count) = self.outstanding.get_mut(&pending.dest) {
+            *count -= 1;
+            if *count == 0 { self.outstanding.remove(&pending.dest); }
+        }
+        true
+    }
+
+    /// retransmits anything that's due, if `tag` is this instance's retry timer.
+    ///
+    /// Returns `false` (and does nothing) for any other tag, so it can be called for every interval.
+    pub fn handle_interval(&mut self, ctx: &Context, tag: &str) -> bool {
+        if tag != self.tag { return false; }
+
+        let now = ctx.elapsed();
+        // (a timer that was superseded by an earlier one can still fire later)
+        if self.timer_at.is_some_and(|at| at <= now) {
+            self.timer_at = None;
+        }
+
+        for (key, pending) in self.pending.iter_mut().filter(|(_, pending)| pending.retry_at <= now) {
+            let msg_id = ctx.send(&pending.dest, pending.body.clone());
+            pending.msg_ids.push(msg_id);
+            pending.retries += 1;
+            pending.retry_at = now + backoff(self.initial_backoff, self.max_backoff, pending.retries);
+            self.attempts.insert((pending.dest.clone(), msg_id), *key);
+        }
+
+        if let Some(next) = self.pending.values().map(|pending| pending.retry_at).min() {
+            self.schedule_retry(ctx, next);
+        }
+        true
+    }
+
+    /// number of unacknowledged messages for each peer.  (peers with none are left out)
+    pub fn outstanding(&self) -> &HashMap<NodeId, usize> {
+        &self.outstanding
+    }
+
+    /// number of unacknowledged messages for `peer`.
+    pub fn outstanding_to(&self, peer: &str) -> usize {
+        self.outstanding.get(peer).copied().unwrap_or(0)
+    }
+
+    /// total number of unacknowledged messages.
+    pub fn total_outstanding(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// makes sure a retry timer fires by `at`.
+    fn schedule_retry(&mut self, ctx: &Context, at: Duration) {
+        if self.timer_at.is_some_and(|timer_at| timer_at <= at) { return; }
+
+        self.timer_at = Some(at);
+        ctx.schedule_after(self.tag.clone(), at.saturating_sub(ctx.elapsed()));
+    }
+}
+
+/// `initial`, doubled `retries - 1` times, capped at `max`.
+fn backoff(initial: Duration, max: Duration, retries: u32) -> Duration {
+    let factor = 1u32.checked_shl(retries.saturating_sub(1)).unwrap_or(u32::MAX);
+    initial.saturating_mul(factor).min(max)
+}
+
+
+#[cfg(test)]
+mod reliable_tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::{NodeHandler, sim::Cluster};
+
+    #[test]
+    fn tracks_outstanding_per_peer() {
+        let ctx = Context::detached("n1", vec!["n1".to_string(), "n2".to_string(), "n3".to_string()]);
+        let mut reliable = Reliable::default();
+
+        let first = reliable.send(&ctx, "n2", Body::Broadcast { message: 1 });
+        reliable.send(&ctx, "n2", Body::Broadcast { message: 2 });
+        reliable.send(&ctx, "n3", Body::Broadcast { message: 1 });
+        assert_eq!((reliable.outstanding_to("n2"), reliable.outstanding_to("n3"), reliable.total_outstanding()), (2, 1, 3));
+
+        let mut request = NodeMessage::new("n1".to_string(), "n2".to_string(), Body::Broadcast { message: 1 });
+        request.header.msg_id = Some(first);
+        let reply = request.reply(Body::BroadcastOk);
+
+        assert!(reliable.ack(&reply));
+        assert!(!reliable.ack(&reply), "a duplicate reply shouldn't ack anything else");
+        assert_eq!(reliable.outstanding_to("n2"), 1);
+
+        // (the same msg_id from a different peer isn't a reply to us)
+        let mut wrong_peer = reply.clone();
+        wrong_peer.src = "n3".to_string();
+        assert!(!reliable.ack(&wrong_peer));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let (initial, max) = (Duration::from_millis(100), Duration::from_millis(500));
+        let backoffs: Vec<_> = (1..=5).map(|retries| backoff(initial, max, retries).as_millis()).collect();
+        assert_eq!(backoffs, vec![100, 200, 400, 500, 500]);
+        assert_eq!(backoff(initial, max, 100), max);
+    }
+
+    /// reliably forwards a client's `broadcast` to every other node, which only reply `broadcast_ok`.
+    struct SenderNode {
+        reliable: Reliable,
+        // (the messages each node has received from a peer, and its outstanding count, for the test to inspect)
+        received: Arc<Mutex<HashMap<NodeId, Vec<usize>>>>,
+        outstanding: Arc<Mutex<usize>>,
+    }
+
+    impl NodeHandler for SenderNode {
+        fn handle_msg(&mut self, ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+            match &msg.body {
+                Body::Broadcast { message } if msg.src.starts_with('c') => {
+                    for dest in ctx.node_ids().iter().filter(|id| *id != ctx.node_id()) {
+                        self.reliable.send(ctx, dest, Body::Broadcast { message: *message });
+                    }
+                    Some(vec![msg.reply(Body::BroadcastOk)])
+                },
+                Body::Broadcast { message } => {
+                    self.received.lock().unwrap().entry(ctx.node_id().clone()).or_default().push(*message);
+                    Some(vec![msg.reply(Body::BroadcastOk)])
+                },
+                Body::BroadcastOk => {
+                    self.reliable.ack(&msg);
+                    *self.outstanding.lock().unwrap() = self.reliable.total_outstanding();
+                    None
+                },
+                _ => None,
+            }
+        }
+
+        fn handle_interval(&mut self, ctx: &Context, tag: Tag, _elapsed: Duration) -> Option<Vec<NodeMessage>> {
+            self.reliable.handle_interval(ctx, &tag);
+            None
+        }
+    }
+
+    #[test]
+    fn retries_until_acknowledged() {
+        let received = Arc::new(Mutex::new(HashMap::new()));
+        let outstanding = Arc::new(Mutex::new(0));
+        let mut cluster = Cluster::new(3, 7, |_| Box::new(SenderNode {
+                reliable: Reliable::default(),
+                received: received.clone(),
+                outstanding: outstanding.clone(),
+            }))
+            .latency(Duration::from_millis(1), Duration::from_millis(10))
+            .loss(0.5);
+
+        for message in 0..10 {
+            cluster.client_request("c1", "n1", Body::Broadcast { message });
+        }
+        cluster.run_for(Duration::from_secs(60));
+
+        assert!(cluster.stats().lost > 0, "loss should have dropped some messages");
+        assert_eq!(*outstanding.lock().unwrap(), 0);
+
+        // (at least once: retries mean some may have arrived more than once)
+        let received = received.lock().unwrap();
+        for node_id in ["n0", "n2"] {
+            let mut messages = received[node_id].clone();
+            messages.sort();
+            messages.dedup();
+            assert_eq!(messages, (0..10).collect::<Vec<_>>(), "{} missed a message", node_id);
+        }
+    }
+}
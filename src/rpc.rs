@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex, OnceLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{sync::{mpsc, oneshot}, time::{self, Instant, Sleep}};
+
+use crate::data_models::*;
+
+
+/// Errors surfaced while waiting on the reply to an rpc request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcError {
+    /// no reply with a matching `in_reply_to` arrived before the request's timeout.
+    Timeout,
+    /// the runner went away (or cleaned up the request) before a reply arrived.
+    Closed,
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "rpc request timed out"),
+            RpcError::Closed => write!(f, "rpc request was dropped before a reply arrived"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+
+struct PendingRequest {
+    deadline: Instant,
+    reply_tx: oneshot::Sender<NodeMessage>,
+}
+
+/// A cloneable handle for sending messages through a `NodeRunner`.
+///
+/// Every message sent through the client is stamped with the next available `msg_id`,
+/// which is handed back to the caller.  Requests sent via `call()`/`rpc()` are tracked
+/// until a message arrives with a matching `in_reply_to`, or until their timeout passes.
+///
+/// The client can be moved into spawned tasks, and keeps working while `run_node()` is running.
+#[derive(Clone)]
+pub struct RpcClient {
+    // only known once the node has been sent `init`
+    node_id: Arc<OnceLock<NodeId>>,
+    next_msg_id: Arc<AtomicUsize>,
+    msg_tx: mpsc::UnboundedSender<NodeMessage>,
+    pending: Arc<Mutex<HashMap<MsgId, PendingRequest>>>,
+}
+
+impl RpcClient {
+    pub(crate) fn new(node_id: NodeId, msg_tx: mpsc::UnboundedSender<NodeMessage>) -> Self {
+        let client = Self::unassigned(msg_tx);
+        client.assign_node_id(node_id);
+        client
+    }
+
+    /// a client for a node that hasn't been sent `init` yet.  (see `assign_node_id()`)
+    pub(crate) fn unassigned(msg_tx: mpsc::UnboundedSender<NodeMessage>) -> Self {
+        Self {
+            node_id: Arc::new(OnceLock::new()),
+            next_msg_id: Arc::new(AtomicUsize::new(0)),
+            msg_tx,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// NodeId of the node this client sends messages for.  (empty until the node has been sent `init`)
+    pub fn node_id(&self) -> &NodeId {
+        static UNASSIGNED: NodeId = NodeId::new();
+        self.node_id.get().unwrap_or(&UNASSIGNED)
+    }
+
+    /// sets the node id, for this client and every clone of it.  Only the first call has any effect.
+    pub(crate) fn assign_node_id(&self, node_id: NodeId) {
+        let _ = self.node_id.set(node_id);
+    }
+
+    /// assigns the message the next available `msg_id`, then sends it.
+    ///
+    /// Returns the `msg_id` the message was sent with.
+    /// (messages sent after the runner has shut down are dropped)
+    pub fn send(&self, mut msg: NodeMessage) -> MsgId {
+        let msg_id = self.next_msg_id();
+        msg.header.msg_id = Some(msg_id);
+
+        let _ = self.msg_tx.send(msg);
+
+        msg_id
+    }
+
+    /// sends the message as a request, and tracks it until a reply arrives or `timeout` elapses.
+    ///
+    /// The returned `PendingReply` exposes the `msg_id` the request was sent with,
+    /// and resolves to the reply once it arrives.
+    pub async fn call(&self, mut msg: NodeMessage, timeout: Duration) -> PendingReply {
+        let msg_id = self.next_msg_id();
+        msg.header.msg_id = Some(msg_id);
+
+        let deadline = Instant::now() + timeout;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending.lock().unwrap()
+            .insert(msg_id, PendingRequest { deadline, reply_tx });
+
+        if self.msg_tx.send(msg).is_err() {
+            self.pending.lock().unwrap().remove(&msg_id);
+        }
+
+        PendingReply {
+            msg_id,
+            reply_rx,
+            timeout: Box::pin(time::sleep_until(deadline)),
+        }
+    }
+
+    /// sends the message as a request, and waits for the matching reply.
+    pub async fn rpc(&self, msg: NodeMessage, timeout: Duration) -> Result<NodeMessage, RpcError> {
+        self.call(msg, timeout).await.await
+    }
+
+    /// number of requests still waiting on a reply.
+    pub fn pending_count(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// hands `msg` to the request it replies to, if that request is still waiting.
+    ///
+    /// Returns the message back when nothing was waiting on it.
+    pub(crate) fn resolve(&self, msg: NodeMessage) -> Option<NodeMessage> {
+        let pending = msg.header.in_reply_to
+            .and_then(|id| self.pending.lock().unwrap().remove(&id));
+
+        match pending {
+            Some(request) => request.reply_tx.send(msg).err(),
+            None => Some(msg),
+        }
+    }
+
+    /// drops any requests that are past their deadline, or that nobody is waiting on anymore.
+    pub(crate) fn expire_pending(&self) {
+        let now = Instant::now();
+        self.pending.lock().unwrap()
+            .retain(|_, request| request.deadline > now && !request.reply_tx.is_closed());
+    }
+
+    fn next_msg_id(&self) -> MsgId {
+        self.next_msg_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+}
+
+
+/// The reply side of a request sent via `RpcClient::call()`.
+///
+/// Resolves to the reply message, or `RpcError::Timeout` once the request's timeout passes.
+pub struct PendingReply {
+    msg_id: MsgId,
+    reply_rx: oneshot::Receiver<NodeMessage>,
+    timeout: Pin<Box<Sleep>>,
+}
+
+impl PendingReply {
+    /// the `msg_id` the request was sent with.
+    pub fn msg_id(&self) -> MsgId {
+        self.msg_id
+    }
+}
+
+impl Future for PendingReply {
+    type Output = Result<NodeMessage, RpcError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(reply) = Pin::new(&mut self.reply_rx).poll(cx) {
+            return Poll::Ready(reply.map_err(|_| RpcError::Closed));
+        }
+
+        match self.timeout.as_mut().poll(cx) {
+            Poll::Ready(_) => Poll::Ready(Err(RpcError::Timeout)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod rpc_tests {
+    use super::*;
+
+    fn echo_request(dest: &str) -> NodeMessage {
+        NodeMessage::new("n1".to_string(), dest.to_string(), Body::Echo { echo: "hello".to_string() })
+    }
+
+    #[tokio::test]
+    async fn resolves_matching_reply() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let client = RpcClient::new("n1".to_string(), tx);
+
+        let pending = client.call(echo_request("n2"), Duration::from_secs(1)).await;
+        let sent = rx.recv().await.unwrap();
+        assert_eq!(sent.header.msg_id, Some(pending.msg_id()));
+
+        let reply = sent.reply(Body::EchoOk { echo: "hello".to_string() });
+        assert!(client.resolve(reply).is_none());
+
+        let reply = pending.await.unwrap();
+        assert_eq!(reply.header.in_reply_to, sent.header.msg_id);
+        assert_eq!(client.pending_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn unmatched_reply_is_returned() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let client = RpcClient::new("n1".to_string(), tx);
+
+        let mut request = echo_request("n2");
+        request.header.msg_id = Some(42);
+
+        let reply = request.reply(Body::EchoOk { echo: "hello".to_string() });
+        assert!(client.resolve(reply).is_some());
+    }
+
+    #[tokio::test]
+    async fn times_out_and_cleans_up() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let client = RpcClient::new("n1".to_string(), tx);
+
+        let result = client.rpc(echo_request("n2"), Duration::from_millis(50)).await;
+        assert_eq!(result.unwrap_err(), RpcError::Timeout);
+        assert_eq!(client.pending_count(), 1);
+
+        client.expire_pending();
+        assert_eq!(client.pending_count(), 0);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{fmt::Display, time::Duration};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{NodeRunner, data_models::*, transport::Transport, rpc::{RpcClient, RpcError}};
+
+/// how long a KV request waits on the service before giving up.
+const DEFAULT_KV_TIMEOUT: Duration = Duration::from_millis(1000);
+
+
+/// Maelstrom's built-in key/value services.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvService {
+    /// linearizable kv store
+    LinKv,
+    /// sequentially consistent kv store
+    SeqKv,
+    /// last-write-wins kv store
+    LwwKv,
+}
+
+impl Display for KvService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvService::LinKv => write!(f, "lin-kv"),
+            KvService::SeqKv => write!(f, "seq-kv"),
+            KvService::LwwKv => write!(f, "lww-kv"),
+        }
+    }
+}
+
+
+/// Errors returned by a `KvClient` operation.
+#[derive(Debug)]
+pub enum KvError {
+    /// the requested key has never been written.
+    KeyDoesNotExist,
+    /// a `cas` found a value other than `from`.
+    PreconditionFailed(String),
+    /// any other `error` reply from the service.
+    Service { code: ErrorCode, text: String },
+    /// the request never got a reply.
+    Rpc(RpcError),
+    /// the service replied with a message type that doesn't answer the request.
+    UnexpectedReply(Body),
+    /// a key or value couldn't be converted to/from json.
+    Serde(serde_json::Error),
+}
+
+impl Display for KvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist => write!(f, "key does not exist"),
+            KvError::PreconditionFailed(text) => write!(f, "precondition failed: {}", text),
+            KvError::Service { code, text } => write!(f, "kv service error ({}): {}", code, text),
+            KvError::Rpc(err) => write!(f, "{}", err),
+            KvError::UnexpectedReply(body) => write!(f, "unexpected reply from kv service: {:?}", body),
+            KvError::Serde(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+impl KvError {
+    /// the error code to pass this on to a client with.
+    ///
+    /// (a request that never got a reply is a `timeout`, since it may or may not have taken effect)
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvError::KeyDoesNotExist => ErrorCode::KeyDoesNotExist,
+            KvError::PreconditionFailed(_) => ErrorCode::PreconditionFailed,
+            KvError::Service { code, text: _ } => *code,
+            KvError::Rpc(_) => ErrorCode::Timeout,
+            KvError::UnexpectedReply(_) | KvError::Serde(_) => ErrorCode::Crash,
+        }
+    }
+}
+
+impl From<KvError> for Body {
+    fn from(err: KvError) -> Self {
+        Body::Error { code: err.code(), text: err.to_string() }
+    }
+}
+
+impl From<RpcError> for KvError {
+    fn from(err: RpcError) -> Self { KvError::Rpc(err) }
+}
+
+impl From<serde_json::Error> for KvError {
+    fn from(err: serde_json::Error) -> Self { KvError::Serde(err) }
+}
+
+
+/// A typed client for one of Maelstrom's KV services.
+///
+/// Requests are sent through the `NodeRunner`'s `RpcClient`, so replies only arrive while `run_node()` is running.
+#[derive(Clone)]
+pub struct KvClient {
+    client: RpcClient,
+    service: KvService,
+    timeout: Duration,
+}
+
+impl KvClient {
+    /// create a client for `service`, bound to the `runner` it sends messages through.
+    pub fn new<T: Transport>(runner: &NodeRunner<T>, service: KvService) -> Self {
+        Self::with_client(runner.rpc_client(), service)
+    }
+
+    pub fn lin_kv<T: Transport>(runner: &NodeRunner<T>) -> Self { Self::new(runner, KvService::LinKv) }
+    pub fn seq_kv<T: Transport>(runner: &NodeRunner<T>) -> Self { Self::new(runner, KvService::SeqKv) }
+    pub fn lww_kv<T: Transport>(runner: &NodeRunner<T>) -> Self { Self::new(runner, KvService::LwwKv) }
+
+    /// create a client for `service` that sends messages through an existing `RpcClient`.
+    pub fn with_client(client: RpcClient, service: KvService) -> Self {
+        Self { client, service, timeout: DEFAULT_KV_TIMEOUT }
+    }
+
+    /// sets how long each request waits on the service before failing with `RpcError::Timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn service(&self) -> KvService {
+        self.service
+    }
+
+    /// reads the current value of `key`.
+    pub async fn read<K: Serialize, V: DeserializeOwned>(&self, key: K) -> Result<V, KvError> {
+        let key = serde_json::to_value(key)?;
+        match self.request(Body::Read { key: Some(key) }).await? {
+            Body::ReadOk { value: Some(value), .. } => Ok(serde_json::from_value(value)?),
+            body => Err(KvError::UnexpectedReply(body)),
+        }
+    }
+
+    /// sets `key` to `value`.
+    pub async fn write<K: Serialize, V: Serialize>(&self, key: K, value: V) -> Result<(), KvError> {
+        let key = serde_json::to_value(key)?;
+        let value = serde_json::to_value(value)?;
+        match self.request(Body::Write { key, value }).await? {
+            Body::WriteOk => Ok(()),
+            body => Err(KvError::UnexpectedReply(body)),
+        }
+    }
+
+    /// sets `key` to `to`, only if its current value is `from`.
+    ///
+    /// With `create_if_not_exists`, a missing key is treated as holding `from`.
+    pub async fn cas<K: Serialize, V: Serialize>(&self, key: K, from: V, to: V, create_if_not_exists: bool) -> Result<(), KvError> {
+        let key = serde_json::to_value(key)?;
+        let from = serde_json::to_value(from)?;
+        let to = serde_json::to_value(to)?;
+        match self.request(Body::Cas { key, from, to, create_if_not_exists }).await? {
+            Body::CasOk => Ok(()),
+            body => Err(KvError::UnexpectedReply(body)),
+        }
+    }
+
+    /// read-modify-writes `key` with a `cas`, retrying whenever another writer changed it in between.
+    ///
+    /// A missing key is treated as holding `default`.  `update` is given the current value and returns
+    /// its replacement, or `None` to leave it as is.  Returns the value the key was left holding.
+    pub async fn update<K, V, F>(&self, key: K, default: V, mut update: F) -> Result<V, KvError>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned + Clone,
+        F: FnMut(&V) -> Option<V>,
+    {
+        loop {
+            let current = match self.read(&key).await {
+                Err(KvError::KeyDoesNotExist) => default.clone(),
+                result => result?,
+            };
+            let Some(updated) = update(&current) else { return Ok(current) };
+
+            match self.cas(&key, current, updated.clone(), true).await {
+                Ok(_) => return Ok(updated),
+                Err(KvError::PreconditionFailed(_)) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// sends `body` to the service, and maps `error` replies to a `KvError`.
+    async fn request(&self, body: Body) -> Result<Body, KvError> {
+        let msg = NodeMessage::new(self.client.node_id().clone(), self.service.to_string(), body);
+
+        match self.client.rpc(msg, self.timeout).await?.body {
+            Body::Error { code: ErrorCode::KeyDoesNotExist, .. } => Err(KvError::KeyDoesNotExist),
+            Body::Error { code: ErrorCode::PreconditionFailed, text, .. } => Err(KvError::PreconditionFailed(text)),
+            Body::Error { code, text, .. } => Err(KvError::Service { code, text }),
+            body => Ok(body),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod services_tests {
+    use super::*;
+    use std::collections::HashMap;
+    use serde_json::Value;
+    use tokio::sync::mpsc;
+
+    /// stands in for a Maelstrom kv service: answers every request `client` sends.
+    fn spawn_in_memory_kv(client: RpcClient, mut msg_rx: mpsc::UnboundedReceiver<NodeMessage>) {
+        tokio::spawn(async move {
+            let mut store: HashMap<String, Value> = HashMap::new();
+
+            while let Some(msg) = msg_rx.recv().await {
+                let error = |code, text: &str| Body::Error { code, text: text.to_string() };
+
+                let body = match msg.body.clone() {
+                    Body::Read { key: Some(key) } => match store.get(&key.to_string()) {
+                        Some(value) => Body::ReadOk { messages: None, value: Some(value.clone()) },
+                        None => error(ErrorCode::KeyDoesNotExist, "not found"),
+                    },
+                    Body::Write { key, value } => {
+                        store.insert(key.to_string(), value);
+                        Body::WriteOk
+                    },
+                    Body::Cas { key, from, to, create_if_not_exists } => match store.get(&key.to_string()) {
+                        Some(current) if *current == from => {
+                            store.insert(key.to_string(), to);
+                            Body::CasOk
+                        },
+                        Some(current) => error(ErrorCode::PreconditionFailed, &format!("expected {}, had {}", from, current)),
+                        None if create_if_not_exists => {
+                            store.insert(key.to_string(), to);
+                            Body::CasOk
+                        },
+                        None => error(ErrorCode::KeyDoesNotExist, "not found"),
+                    },
+                    _ => error(ErrorCode::NotSupported, "unsupported"),
+                };
+
+                client.resolve(msg.reply(body));
+            }
+        });
+    }
+
+    fn kv_client() -> KvClient {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let client = RpcClient::new("n1".to_string(), tx);
+        spawn_in_memory_kv(client.clone(), rx);
+
+        KvClient::with_client(client, KvService::LinKv)
+    }
+
+    #[tokio::test]
+    async fn write_then_read() {
+        let kv = kv_client();
+
+        kv.write("counter", 5).await.unwrap();
+        let value: i64 = kv.read("counter").await.unwrap();
+        assert_eq!(value, 5);
+    }
+
+    #[tokio::test]
+    async fn read_missing_key() {
+        let kv = kv_client();
+
+        let result = kv.read::<_, i64>("missing").await;
+        assert!(matches!(result, Err(KvError::KeyDoesNotExist)));
+    }
+
+    #[tokio::test]
+    async fn cas_checks_precondition() {
+        let kv = kv_client();
+
+        assert!(matches!(kv.cas("k", 0, 1, false).await, Err(KvError::KeyDoesNotExist)));
+        kv.cas("k", 0, 1, true).await.unwrap();
+        assert!(matches!(kv.cas("k", 0, 2, false).await, Err(KvError::PreconditionFailed(_))));
+        kv.cas("k", 1, 2, false).await.unwrap();
+
+        let value: i64 = kv.read("k").await.unwrap();
+        assert_eq!(value, 2);
+    }
+
+    #[tokio::test]
+    async fn update_reads_then_cases() {
+        let kv = kv_client();
+
+        assert_eq!(kv.update("k", 10, |value| Some(value + 1)).await.unwrap(), 11);
+        assert_eq!(kv.update("k", 10, |value| Some(value * 2)).await.unwrap(), 22);
+        assert_eq!(kv.update("k", 10, |_: &i64| None).await.unwrap(), 22);
+        assert_eq!(kv.update("missing", 10, |_: &i64| None).await.unwrap(), 10);
+        assert!(matches!(kv.read::<_, i64>("missing").await, Err(KvError::KeyDoesNotExist)));
+    }
+
+    #[test]
+    fn errors_keep_definite_codes() {
+        assert_eq!(KvError::KeyDoesNotExist.code(), ErrorCode::KeyDoesNotExist);
+        assert_eq!(KvError::PreconditionFailed(String::new()).code(), ErrorCode::PreconditionFailed);
+        assert_eq!(KvError::Rpc(RpcError::Timeout).code(), ErrorCode::Timeout);
+
+        let body: Body = KvError::Service { code: ErrorCode::TxnConflict, text: "conflict".to_string() }.into();
+        assert!(matches!(body, Body::Error { code: ErrorCode::TxnConflict, .. }));
+    }
+}
@@ -0,0 +1,809 @@
+use std::{cmp::{Ordering, Reverse}, collections::{BinaryHeap, HashMap}, sync::{atomic::{self, AtomicU64}, Arc}, time::Duration};
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+use tokio::sync::mpsc;
+
+use crate::{NodeHandler, Tag, check::History, context::{Clock, Context, Timer}, data_models::*, rpc::RpcClient};
+
+
+/// A deterministic, in-process cluster of `NodeHandler`s.
+///
+/// Messages returned by the handlers are routed through a virtual network, and
+/// registered intervals fire on a virtual clock, so a whole run happens inside a
+/// single `cargo test` without Maelstrom.  Any randomness (ie message latency, loss,
+/// or partitions) comes from a seeded RNG, so the same seed always replays the same run
+/// (as long as the handlers themselves are deterministic).
+///
+/// Messages sent to an id that isn't a node in the cluster (ie a client like `c1`)
+/// are collected, and can be inspected with `client_messages()`, or checked as a
+/// whole with the `history()`.
+/// Faults are only ever applied to messages between two nodes, never to/from clients
+/// (the same as Maelstrom's nemeses).
+pub struct Cluster {
+    node_ids: Vec<NodeId>,
+    nodes: HashMap<NodeId, SimNode>,
+
+    now: Duration,
+    /// `now`, shared with every node's `Context`.
+    clock: Arc<AtomicU64>,
+    rng: StdRng,
+    faults: Faults,
+    stats: NetworkStats,
+
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    next_seq: u64,
+
+    intervals: Vec<SimInterval>,
+    timers: Vec<SimTimer>,
+
+    /// scripted partition changes, and the state of any random partitions.
+    partition_events: Vec<(Duration, PartitionEvent)>,
+    random_partitions: Option<Duration>,
+    /// which side of a partition each node is on.  Nodes on different sides can't talk.
+    partition: Option<HashMap<NodeId, usize>>,
+
+    next_client_msg_id: MsgId,
+    client_msgs: Vec<NodeMessage>,
+    history: History,
+}
+
+struct SimNode {
+    handler: Box<dyn NodeHandler>,
+    ctx: Context,
+    /// messages sent through `ctx`, waiting to be put on the network.
+    outbox: mpsc::UnboundedReceiver<NodeMessage>,
+    /// timers scheduled through `ctx`, waiting to be put on the virtual clock.
+    scheduled: mpsc::UnboundedReceiver<Timer>,
+}
+
+struct SimInterval {
+    tag: Tag,
+    every: Duration,
+    next_at: Duration,
+}
+
+/// a one-shot timer, from `Context::schedule_after()`.
+struct SimTimer {
+    node_id: NodeId,
+    tag: Tag,
+    at: Duration,
+}
+
+/// a message on the virtual network, ordered by delivery time (ties broken by send order).
+struct InFlight {
+    deliver_at: Duration,
+    seq: u64,
+    msg: NodeMessage,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool { self.cmp(other) == Ordering::Equal }
+}
+impl Eq for InFlight {}
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.seq).cmp(&(other.deliver_at, other.seq))
+    }
+}
+
+enum PartitionEvent {
+    /// split the nodes into these groups.
+    Split(Vec<Vec<NodeId>>),
+    /// split the nodes into two random halves, or heal, whichever is the opposite of now.
+    RandomToggle,
+    Heal,
+}
+
+enum Event {
+    Deliver,
+    Interval(usize),
+    Timer(usize),
+    Partition(usize),
+}
+
+
+/// How long a message takes to cross the virtual network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// uniformly distributed between `min` and `max` (inclusive).
+    Uniform { min: Duration, max: Duration },
+    /// exponentially distributed around `mean` (the shape of Maelstrom's `--latency-dist exponential`).
+    Exponential { mean: Duration },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform { min, max } if max > min => rng.gen_range(min..=max),
+            Latency::Uniform { min, max: _ } => min,
+            Latency::Exponential { mean } => {
+                let u: f64 = rng.gen_range(0.0..1.0);
+                mean.mul_f64(-(1.0 - u).ln())
+            },
+        }
+    }
+}
+
+impl Default for Latency {
+    fn default() -> Self { Latency::Fixed(Duration::ZERO) }
+}
+
+
+/// The faults the virtual network injects into node-to-node messages.
+#[derive(Debug, Clone, Default)]
+struct Faults {
+    latency: Latency,
+    link_latency: HashMap<(NodeId, NodeId), Latency>,
+    /// chance that a message is dropped.
+    loss: f64,
+    /// chance that a message is delivered twice.
+    duplicate: f64,
+    /// chance that a message is held back by up to `reorder_delay`, letting later messages overtake it.
+    reorder: f64,
+    reorder_delay: Duration,
+}
+
+/// Counts of what the virtual network did with node-to-node messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub sent: usize,
+    pub delivered: usize,
+    /// dropped at random (see `Cluster::loss()`)
+    pub lost: usize,
+    /// dropped because the sender and receiver were on different sides of a partition
+    pub partitioned: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+}
+
+
+impl Cluster {
+    /// create a cluster of `node_count` nodes (`n0`, `n1`, ...), with a handler from `make_handler` for each.
+    ///
+    /// Each handler is sent `init` before this returns.
+    pub fn new<F>(node_count: usize, seed: u64, mut make_handler: F) -> Self
+    where
+        F: FnMut(&NodeId) -> Box<dyn NodeHandler>,
+    {
+        let node_ids: Vec<NodeId> = (0..node_count)
+            .map(|i| format!("n{}", i))
+            .collect();
+
+        let clock = Arc::new(AtomicU64::new(0));
+        let nodes = node_ids.iter()
+            .map(|id| {
+                let (msg_tx, outbox) = mpsc::unbounded_channel();
+                let (timer_tx, scheduled) = mpsc::unbounded_channel();
+                let client = RpcClient::new(id.clone(), msg_tx);
+                let ctx = Context::new(client, node_ids.clone(), Clock::Virtual(clock.clone()), timer_tx);
+
+                let mut handler = make_handler(id);
+                handler.init(id.clone(), node_ids.clone());
+                (id.clone(), SimNode { handler, ctx, outbox, scheduled })
+            })
+            .collect();
+
+        Self {
+            node_ids,
+            nodes,
+            now: Duration::ZERO,
+            clock,
+            rng: StdRng::seed_from_u64(seed),
+            faults: Faults::default(),
+            stats: NetworkStats::default(),
+            in_flight: BinaryHeap::new(),
+            next_seq: 0,
+            intervals: Vec::new(),
+            timers: Vec::new(),
+            partition_events: Vec::new(),
+            random_partitions: None,
+            partition: None,
+            next_client_msg_id: 0,
+            client_msgs: Vec::new(),
+            history: History::new(),
+        }
+    }
+
+    /// every message takes between `min` and `max` (inclusive) to be delivered, picked by the seeded RNG.
+    pub fn latency(self, min: Duration, max: Duration) -> Self {
+        self.latency_dist(Latency::Uniform { min, max })
+    }
+
+    /// sets the latency of every link without a `link_latency()` of its own.
+    pub fn latency_dist(mut self, latency: Latency) -> Self {
+        self.faults.latency = latency;
+        self
+    }
+
+    /// sets the latency of messages sent from `src` to `dest`.
+    pub fn link_latency(mut self, src: &str, dest: &str, latency: Latency) -> Self {
+        self.faults.link_latency.insert((src.to_string(), dest.to_string()), latency);
+        self
+    }
+
+    /// drops each node-to-node message with probability `p`.
+    pub fn loss(mut self, p: f64) -> Self {
+        self.faults.loss = p;
+        self
+    }
+
+    /// delivers each node-to-node message twice with probability `p`.
+    pub fn duplicate(mut self, p: f64) -> Self {
+        self.faults.duplicate = p;
+        self
+    }
+
+    /// holds back each node-to-node message with probability `p`, by up to `max_delay` on top of its latency.
+    pub fn reorder(mut self, p: f64, max_delay: Duration) -> Self {
+        self.faults.reorder = p;
+        self.faults.reorder_delay = max_delay;
+        self
+    }
+
+    /// every `every` of virtual time, either splits the nodes into two random halves (the odd node out joins the second), or heals the current partition.
+    ///
+    /// (the same shape as Maelstrom's `--nemesis partition`)
+    pub fn random_partitions(mut self, every: Duration) -> Self {
+        self.random_partitions = Some(every);
+        self.partition_events.push((self.now + every, PartitionEvent::RandomToggle));
+        self
+    }
+
+    /// at virtual time `at`, splits the nodes into `groups`.
+    ///
+    /// Nodes in different groups can't reach each other, and any node not listed can reach everyone.
+    pub fn partition_at(&mut self, at: Duration, groups: &[&[&str]]) {
+        let groups = groups.iter()
+            .map(|group| group.iter().map(|id| id.to_string()).collect())
+            .collect();
+        self.partition_events.push((at, PartitionEvent::Split(groups)));
+    }
+
+    /// at virtual time `at`, heals any partition.
+    pub fn heal_at(&mut self, at: Duration) {
+        self.partition_events.push((at, PartitionEvent::Heal));
+    }
+
+    /// fires `handle_interval(tag)` on every node, every `every` of virtual time.
+    pub fn register_interval(&mut self, tag: Tag, every: Duration) {
+        self.intervals.push(SimInterval { tag, every, next_at: self.now + every });
+    }
+
+    pub fn node_ids(&self) -> &[NodeId] {
+        &self.node_ids
+    }
+
+    /// the current virtual time, since the cluster was created.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+
+    /// `true` if a partition currently stops `src` from reaching `dest`.
+    pub fn is_partitioned(&self, src: &str, dest: &str) -> bool {
+        let Some(partition) = &self.partition else { return false };
+        match (partition.get(src), partition.get(dest)) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        }
+    }
+
+    /// sends a request from a client (any id that isn't a node), and returns the `msg_id` it was sent with.
+    pub fn client_request(&mut self, client: &str, dest: &str, body: Body) -> MsgId {
+        self.next_client_msg_id += 1;
+        let msg_id = self.next_client_msg_id;
+
+        let mut msg = NodeMessage::new(client.to_string(), dest.to_string(), body);
+        msg.header.msg_id = Some(msg_id);
+        self.history.invoke(self.now, &msg);
+        self.enqueue(msg);
+
+        msg_id
+    }
+
+    /// every message delivered to a client so far.
+    pub fn client_messages(&self) -> &[NodeMessage] {
+        &self.client_msgs
+    }
+
+    /// every client request so far, with the replies they received, for checking with `chaos::check`.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// the reply a client received for the request sent with `msg_id`, if it has arrived.
+    pub fn reply_to(&self, client: &str, msg_id: MsgId) -> Option<&NodeMessage> {
+        self.client_msgs.iter()
+            .find(|msg| msg.dest == client && msg.header.in_reply_to == Some(msg_id))
+    }
+
+    /// number of messages still travelling through the network.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// processes the next event (a message delivery, an interval, or a partition change),
+    /// advancing the virtual clock to it.
+    ///
+    /// Returns `false` once there is nothing left to do.
+    pub fn step(&mut self) -> bool {
+        let Some((at, event)) = self.next_event() else { return false };
+        self.advance_to(at);
+
+        match event {
+            Event::Deliver => self.deliver_next(),
+            Event::Interval(idx) => self.fire_interval(idx),
+            Event::Timer(idx) => self.fire_timer(idx),
+            Event::Partition(idx) => self.change_partition(idx),
+        }
+        true
+    }
+
+    /// processes every event up to `duration` past the current virtual time.
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.now + duration;
+        while self.next_event().is_some_and(|(at, _)| at <= until) {
+            self.step();
+        }
+        self.advance_to(until);
+    }
+
+    /// calls `on_shutdown()` on every node, and puts anything they send on the network.
+    ///
+    /// (the cluster can keep running afterwards, ie to deliver those messages)
+    pub fn shutdown(&mut self) {
+        for node_id in self.node_ids.clone() {
+            let node = self.nodes.get_mut(&node_id).unwrap();
+            node.handler.on_shutdown(&node.ctx);
+            self.flush(&node_id, None);
+        }
+    }
+
+    /// delivers messages until none are in flight, or `limit` of virtual time has passed.
+    ///
+    /// Returns `true` if the network went quiet.
+    pub fn run_until_quiet(&mut self, limit: Duration) -> bool {
+        let until = self.now + limit;
+        while !self.in_flight.is_empty() {
+            if self.next_event().is_some_and(|(at, _)| at > until) { return false; }
+            self.step();
+        }
+        true
+    }
+
+    fn advance_to(&mut self, at: Duration) {
+        self.now = self.now.max(at);
+        self.clock.store(self.now.as_nanos() as u64, atomic::Ordering::Relaxed);
+    }
+
+    /// the earliest pending event.  On a tie, messages go first, then intervals and timers, then partition changes.
+    fn next_event(&self) -> Option<(Duration, Event)> {
+        let next_msg = self.in_flight.peek()
+            .map(|Reverse(m)| (m.deliver_at, Event::Deliver));
+        let next_interval = self.intervals.iter()
+            .enumerate()
+            .min_by_key(|(_, interval)| interval.next_at)
+            .map(|(idx, interval)| (interval.next_at, Event::Interval(idx)));
+        let next_timer = self.timers.iter()
+            .enumerate()
+            .min_by_key(|(_, timer)| timer.at)
+            .map(|(idx, timer)| (timer.at, Event::Timer(idx)));
+        let next_partition = self.partition_events.iter()
+            .enumerate()
+            .min_by_key(|(_, (at, _))| *at)
+            .map(|(idx, (at, _))| (*at, Event::Partition(idx)));
+
+        [next_msg, next_interval, next_timer, next_partition].into_iter()
+            .flatten()
+            .min_by_key(|(at, _)| *at)
+    }
+
+    fn deliver_next(&mut self) {
+        let Some(Reverse(in_flight)) = self.in_flight.pop() else { return };
+        let msg = in_flight.msg;
+
+        if !self.nodes.contains_key(&msg.dest) {
+            self.history.complete(self.now, &msg);
+            self.client_msgs.push(msg);
+            return;
+        }
+
+        if self.nodes.contains_key(&msg.src) {
+            if self.is_partitioned(&msg.src, &msg.dest) {
+                self.stats.partitioned += 1;
+                return;
+            }
+            self.stats.delivered += 1;
+        }
+
+        let node_id = msg.dest.clone();
+        let node = self.nodes.get_mut(&node_id).unwrap();
+
+        // replies to outstanding requests go to whoever is waiting on them
+        let Some(msg) = node.ctx.client().resolve(msg) else { return };
+
+        let responses = node.handler.handle_msg(&node.ctx.for_msg(&msg), msg);
+        self.flush(&node_id, responses);
+    }
+
+    fn fire_interval(&mut self, idx: usize) {
+        let interval = &mut self.intervals[idx];
+        interval.next_at += interval.every;
+        let tag = interval.tag.clone();
+
+        for node_id in self.node_ids.clone() {
+            let node = self.nodes.get_mut(&node_id).unwrap();
+            let msgs = node.handler.handle_interval(&node.ctx, tag.clone(), self.now);
+            self.flush(&node_id, msgs);
+        }
+    }
+
+    fn fire_timer(&mut self, idx: usize) {
+        let SimTimer { node_id, tag, at: _ } = self.timers.swap_remove(idx);
+
+        let node = self.nodes.get_mut(&node_id).unwrap();
+        let msgs = node.handler.handle_interval(&node.ctx, tag, self.now);
+        self.flush(&node_id, msgs);
+    }
+
+    fn change_partition(&mut self, idx: usize) {
+        let (_, event) = self.partition_events.swap_remove(idx);
+
+        match event {
+            PartitionEvent::Split(groups) => {
+                self.partition = Some(Cluster::sides(groups));
+            },
+            PartitionEvent::Heal => {
+                self.partition = None;
+            },
+            PartitionEvent::RandomToggle => {
+                if self.partition.take().is_none() && self.node_ids.len() > 1 {
+                    let mut shuffled = self.node_ids.clone();
+                    shuffled.shuffle(&mut self.rng);
+                    let (a, b) = shuffled.split_at(shuffled.len() / 2);
+                    self.partition = Some(Cluster::sides(vec![a.to_vec(), b.to_vec()]));
+                }
+                if let Some(every) = self.random_partitions {
+                    self.partition_events.push((self.now + every, PartitionEvent::RandomToggle));
+                }
+            },
+        }
+    }
+
+    fn sides(groups: Vec<Vec<NodeId>>) -> HashMap<NodeId, usize> {
+        groups.into_iter()
+            .enumerate()
+            .flat_map(|(side, group)| group.into_iter().map(move |id| (id, side)))
+            .collect()
+    }
+
+    /// puts everything a node returned, or sent through its `Context`, on the network
+    /// (each stamped with the node's next `msg_id`), and picks up any timers it scheduled.
+    fn flush(&mut self, node_id: &NodeId, returned: Option<Vec<NodeMessage>>) {
+        let node = self.nodes.get_mut(node_id).unwrap();
+        for msg in returned.into_iter().flatten() {
+            node.ctx.client().send(msg);
+        }
+
+        let mut msgs = Vec::new();
+        while let Ok(msg) = node.outbox.try_recv() {
+            msgs.push(msg);
+        }
+        let mut timers = Vec::new();
+        // (a sim node only has the one handler)
+        while let Ok(Timer { tag, after, handler: _ }) = node.scheduled.try_recv() {
+            timers.push(SimTimer { node_id: node_id.clone(), tag, at: self.now + after });
+        }
+
+        self.timers.extend(timers);
+        for msg in msgs {
+            self.enqueue(msg);
+        }
+    }
+
+    fn enqueue(&mut self, msg: NodeMessage) {
+        let between_nodes = self.nodes.contains_key(&msg.src) && self.nodes.contains_key(&msg.dest);
+        if !between_nodes {
+            let latency = self.faults.latency.sample(&mut self.rng);
+            self.push_in_flight(msg, latency);
+            return;
+        }
+
+        self.stats.sent += 1;
+        if self.rng.gen_bool(self.faults.loss.clamp(0.0, 1.0)) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let copies = if self.rng.gen_bool(self.faults.duplicate.clamp(0.0, 1.0)) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        let link = (msg.src.clone(), msg.dest.clone());
+        for _ in 0..copies {
+            let mut latency = self.faults.link_latency.get(&link)
+                .unwrap_or(&self.faults.latency)
+                .sample(&mut self.rng);
+
+            if self.rng.gen_bool(self.faults.reorder.clamp(0.0, 1.0)) {
+                self.stats.reordered += 1;
+                latency += Latency::Uniform { min: Duration::ZERO, max: self.faults.reorder_delay }.sample(&mut self.rng);
+            }
+
+            self.push_in_flight(msg.clone(), latency);
+        }
+    }
+
+    fn push_in_flight(&mut self, msg: NodeMessage, latency: Duration) {
+        self.next_seq += 1;
+        self.in_flight.push(Reverse(InFlight {
+            deliver_at: self.now + latency,
+            seq: self.next_seq,
+            msg,
+        }));
+    }
+}
+
+
+#[cfg(test)]
+mod sim_tests {
+    use super::*;
+    use std::{collections::HashSet, sync::{Arc, atomic::{AtomicUsize, Ordering as AtomicOrdering}}};
+
+    /// floods every new broadcast to all other nodes.
+    #[derive(Default)]
+    struct FloodNode {
+        known: HashSet<usize>,
+    }
+
+    impl NodeHandler for FloodNode {
+        fn handle_msg(&mut self, ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+            match &msg.body {
+                Body::Broadcast { message } => {
+                    if self.known.insert(*message) {
+                        ctx.node_ids().iter()
+                            .filter(|peer| *peer != ctx.node_id())
+                            .for_each(|peer| { ctx.send(peer, Body::Broadcast { message: *message }); });
+                    }
+                    Some(vec![msg.reply(Body::BroadcastOk)])
+                },
+                Body::Read { key: None } => Some(vec![msg.reply(Body::ReadOk { messages: Some(self.known.clone()), value: None })]),
+                _ => None,
+            }
+        }
+    }
+
+    /// counts every interval it sees.
+    struct TickNode {
+        ticks: Arc<AtomicUsize>,
+    }
+
+    impl NodeHandler for TickNode {
+        fn handle_msg(&mut self, _ctx: &Context, _msg: NodeMessage) -> Option<Vec<NodeMessage>> { None }
+
+        fn handle_interval(&mut self, ctx: &Context, _tag: Tag, elapsed: Duration) -> Option<Vec<NodeMessage>> {
+            assert_eq!(elapsed.as_millis() % 100, 0);
+            assert_eq!(ctx.elapsed(), elapsed);
+            self.ticks.fetch_add(1, AtomicOrdering::Relaxed);
+            None
+        }
+    }
+
+    /// answers each echo after a delay, using a one-shot timer.
+    #[derive(Default)]
+    struct DelayedEchoNode {
+        waiting: HashMap<Tag, NodeMessage>,
+    }
+
+    impl NodeHandler for DelayedEchoNode {
+        fn handle_msg(&mut self, ctx: &Context, msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+            let tag = format!("{:?}", msg.header.msg_id);
+            ctx.schedule_after(tag.clone(), Duration::from_millis(200));
+            self.waiting.insert(tag, msg);
+            None
+        }
+
+        fn handle_interval(&mut self, ctx: &Context, tag: Tag, _elapsed: Duration) -> Option<Vec<NodeMessage>> {
+            if let Some(msg) = self.waiting.remove(&tag) {
+                if let Body::Echo { echo } = &msg.body {
+                    ctx.reply(&msg, Body::EchoOk { echo: echo.clone() });
+                }
+            }
+            None
+        }
+    }
+
+    fn flood_cluster(seed: u64) -> Cluster {
+        Cluster::new(5, seed, |_| Box::new(FloodNode::default()))
+            .latency(Duration::from_millis(1), Duration::from_millis(50))
+    }
+
+    #[test]
+    fn broadcasts_converge() {
+        let mut cluster = flood_cluster(7);
+
+        let broadcast_id = cluster.client_request("c1", "n0", Body::Broadcast { message: 42 });
+        assert!(cluster.run_until_quiet(Duration::from_secs(5)));
+        assert!(matches!(cluster.reply_to("c1", broadcast_id).unwrap().body, Body::BroadcastOk));
+
+        for node_id in cluster.node_ids().to_vec() {
+            let read_id = cluster.client_request("c1", &node_id, Body::Read { key: None });
+            cluster.run_until_quiet(Duration::from_secs(1));
+
+            match &cluster.reply_to("c1", read_id).unwrap().body {
+                Body::ReadOk { messages: Some(messages), .. } => assert!(messages.contains(&42)),
+                body => panic!("'read' did not produce a 'read_ok' message: {:?}", body),
+            }
+        }
+
+        assert_eq!(crate::check::broadcast(cluster.history()), Ok(()));
+    }
+
+    #[test]
+    fn same_seed_replays_same_run() {
+        let run = |seed| {
+            let mut cluster = flood_cluster(seed);
+            cluster.client_request("c1", "n0", Body::Broadcast { message: 1 });
+            cluster.client_request("c2", "n3", Body::Broadcast { message: 2 });
+            cluster.run_until_quiet(Duration::from_secs(5));
+            (cluster.now(), serde_json::to_string(cluster.client_messages()).unwrap())
+        };
+
+        assert_eq!(run(3), run(3));
+    }
+
+    #[test]
+    fn intervals_fire_on_virtual_time() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let mut cluster = Cluster::new(3, 1, |_| Box::new(TickNode { ticks: ticks.clone() }));
+        cluster.register_interval("tick".to_string(), Duration::from_millis(100));
+
+        cluster.run_for(Duration::from_millis(450));
+
+        assert_eq!(cluster.now(), Duration::from_millis(450));
+        assert_eq!(ticks.load(AtomicOrdering::Relaxed), 4 * 3);
+    }
+
+    /// reports how many messages it received, when it shuts down.
+    #[derive(Default)]
+    struct CountingNode {
+        received: usize,
+    }
+
+    impl NodeHandler for CountingNode {
+        fn handle_msg(&mut self, _ctx: &Context, _msg: NodeMessage) -> Option<Vec<NodeMessage>> {
+            self.received += 1;
+            None
+        }
+
+        fn on_shutdown(&mut self, ctx: &Context) {
+            ctx.send("c1", Body::Add { delta: self.received as i64 });
+        }
+    }
+
+    #[test]
+    fn shutdown_flushes_final_messages() {
+        let mut cluster = Cluster::new(2, 1, |_| Box::new(CountingNode::default()));
+        cluster.client_request("c1", "n0", Body::Generate);
+        cluster.client_request("c1", "n0", Body::Generate);
+        cluster.run_until_quiet(Duration::from_secs(1));
+
+        cluster.shutdown();
+        cluster.run_until_quiet(Duration::from_secs(1));
+
+        let mut totals: Vec<_> = cluster.client_messages().iter()
+            .map(|msg| match msg.body {
+                Body::Add { delta } => (msg.src.clone(), delta),
+                _ => panic!("unexpected message: {:?}", msg),
+            })
+            .collect();
+        totals.sort();
+        assert_eq!(totals, vec![("n0".to_string(), 2), ("n1".to_string(), 0)]);
+    }
+
+    #[test]
+    fn timers_fire_once_on_virtual_time() {
+        let mut cluster = Cluster::new(1, 1, |_| Box::new(DelayedEchoNode::default()));
+
+        let echo_id = cluster.client_request("c1", "n0", Body::Echo { echo: "hi".to_string() });
+        cluster.run_for(Duration::from_millis(199));
+        assert!(cluster.reply_to("c1", echo_id).is_none());
+
+        cluster.run_for(Duration::from_secs(1));
+        assert!(matches!(&cluster.reply_to("c1", echo_id).unwrap().body, Body::EchoOk { echo } if echo == "hi"));
+        assert_eq!(cluster.client_messages().len(), 1);
+        assert_eq!(crate::check::echo(cluster.history()), Ok(()));
+    }
+
+    fn broadcast_to_all(cluster: &mut Cluster, message: usize) {
+        for node_id in cluster.node_ids().to_vec() {
+            cluster.client_request("c1", &node_id, Body::Broadcast { message });
+        }
+    }
+
+    #[test]
+    fn total_loss_drops_every_node_message() {
+        let mut cluster = flood_cluster(7).loss(1.0);
+
+        cluster.client_request("c1", "n0", Body::Broadcast { message: 1 });
+        cluster.run_until_quiet(Duration::from_secs(5));
+
+        let stats = cluster.stats();
+        assert_eq!(stats.sent, 4);
+        assert_eq!(stats.lost, 4);
+        assert_eq!(stats.delivered, 0);
+
+        // clients still hear back
+        assert_eq!(cluster.client_messages().len(), 1);
+    }
+
+    #[test]
+    fn partitions_block_until_healed() {
+        let mut cluster = flood_cluster(7);
+        cluster.partition_at(Duration::ZERO, &[&["n0", "n1"], &["n2", "n3", "n4"]]);
+        cluster.heal_at(Duration::from_secs(10));
+
+        cluster.run_for(Duration::from_millis(1));
+        assert!(cluster.is_partitioned("n0", "n2"));
+        assert!(!cluster.is_partitioned("n2", "n4"));
+        assert!(!cluster.is_partitioned("c1", "n0"));
+
+        cluster.client_request("c1", "n0", Body::Broadcast { message: 1 });
+        cluster.run_until_quiet(Duration::from_secs(5));
+        assert_eq!(cluster.stats().partitioned, 6);
+
+        cluster.run_for(Duration::from_secs(10));
+        assert!(!cluster.is_partitioned("n0", "n2"));
+    }
+
+    #[test]
+    fn duplicates_are_delivered_twice() {
+        let mut cluster = flood_cluster(7).duplicate(1.0);
+
+        cluster.client_request("c1", "n0", Body::Broadcast { message: 1 });
+        cluster.run_until_quiet(Duration::from_secs(5));
+
+        let stats = cluster.stats();
+        assert_eq!(stats.duplicated, stats.sent);
+        assert_eq!(stats.delivered, 2 * stats.sent);
+    }
+
+    #[test]
+    fn same_seed_replays_same_faults() {
+        let run = |seed| {
+            let mut cluster = flood_cluster(seed)
+                .latency_dist(Latency::Exponential { mean: Duration::from_millis(20) })
+                .loss(0.2)
+                .duplicate(0.1)
+                .reorder(0.3, Duration::from_millis(100))
+                .random_partitions(Duration::from_millis(50));
+            broadcast_to_all(&mut cluster, 1);
+            broadcast_to_all(&mut cluster, 2);
+            cluster.run_until_quiet(Duration::from_secs(5));
+            (cluster.stats(), serde_json::to_string(cluster.client_messages()).unwrap())
+        };
+
+        assert_eq!(run(11), run(11));
+    }
+
+    #[test]
+    fn random_partitions_split_into_halves() {
+        for seed in 0..20 {
+            let mut cluster = flood_cluster(seed).random_partitions(Duration::from_millis(100));
+            cluster.run_for(Duration::from_millis(150));
+
+            let cut_off = cluster.node_ids().iter().filter(|id| cluster.is_partitioned("n0", id)).count();
+            assert!(cut_off == 2 || cut_off == 3, "seed {} cut n0 off from {} of 4 nodes", seed, cut_off);
+        }
+    }
+}
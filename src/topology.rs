@@ -0,0 +1,247 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+use crate::data_models::NodeId;
+
+
+/// Which nodes talk directly to which, built from the `node_ids` a node is given at init.
+///
+/// Every layout is undirected (if `a` neighbors `b`, `b` neighbors `a`), and deterministic for the
+/// same `node_ids` (and seed), so every node builds the same one without coordinating.
+///
+/// Layouts trade messages per operation against latency: `diameter()` is the most hops a broadcast
+/// needs to reach everyone, and `fanout()` the most messages any one node sends to pass it on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topology {
+    neighbors: HashMap<NodeId, BTreeSet<NodeId>>,
+}
+
+impl Topology {
+    /// a topology with no links, ie to `connect()` by hand.
+    pub fn unconnected(node_ids: &[NodeId]) -> Self {
+        Self { neighbors: node_ids.iter().map(|id| (id.clone(), BTreeSet::new())).collect() }
+    }
+
+    /// takes a suggested topology as is, ie Maelstrom's `topology` message.  (links are made undirected)
+    pub fn from_map(topology: &HashMap<NodeId, Vec<NodeId>>) -> Self {
+        let mut layout = Self::default();
+        for (node, neighbors) in topology {
+            layout.neighbors.entry(node.clone()).or_default();
+            for neighbor in neighbors {
+                layout.connect(node, neighbor);
+            }
+        }
+        layout
+    }
+
+    /// a tree where every node has up to `arity` children, filled in `node_ids` order from the first as the root.
+    pub fn tree(node_ids: &[NodeId], arity: usize) -> Self {
+        let arity = arity.max(1);
+        let mut layout = Self::unconnected(node_ids);
+        for (i, node) in node_ids.iter().enumerate().skip(1) {
+            layout.connect(&node_ids[(i - 1) / arity], node);
+        }
+        layout
+    }
+
+    /// every node connected to `hub` only.  (a `hub` that isn't in `node_ids` leaves them unconnected)
+    pub fn star(node_ids: &[NodeId], hub: &str) -> Self {
+        let mut layout = Self::unconnected(node_ids);
+        if !layout.neighbors.contains_key(hub) { return layout; }
+
+        for node in node_ids {
+            layout.connect(hub, node);
+        }
+        layout
+    }
+
+    /// a random graph where each node has at most (and usually exactly) `degree` neighbors, built as
+    /// the union of `degree / 2` random cycles through every node, plus a random matching for an odd `degree`.
+    ///
+    /// With a `degree` of at least 2 it's always connected, with a diameter that grows ~log(n).
+    pub fn expander(node_ids: &[NodeId], degree: usize, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut layout = Self::unconnected(node_ids);
+        if node_ids.len() < 2 { return layout; }
+
+        // (each cycle adds two links per node)
+        for _ in 0..degree / 2 {
+            let mut cycle = node_ids.to_vec();
+            cycle.shuffle(&mut rng);
+            for (i, node) in cycle.iter().enumerate() {
+                layout.connect(node, &cycle[(i + 1) % cycle.len()]);
+            }
+        }
+
+        // (and a matching adds one, leaving the odd node out without it)
+        if degree % 2 == 1 {
+            let mut matching = node_ids.to_vec();
+            matching.shuffle(&mut rng);
+            for pair in matching.chunks_exact(2) {
+                layout.connect(&pair[0], &pair[1]);
+            }
+        }
+        layout
+    }
+
+    /// splits `node_ids` into clusters of `cluster_size`, fully connected within each cluster, with each
+    /// cluster's first node as its leader, and every leader connected to the others.
+    pub fn hierarchical(node_ids: &[NodeId], cluster_size: usize) -> Self {
+        let mut layout = Self::unconnected(node_ids);
+        let clusters: Vec<&[NodeId]> = node_ids.chunks(cluster_size.max(1)).collect();
+
+        for cluster in &clusters {
+            for (i, node) in cluster.iter().enumerate() {
+                for other in &cluster[i + 1..] {
+                    layout.connect(node, other);
+                }
+            }
+        }
+        for (i, cluster) in clusters.iter().enumerate() {
+            for other in &clusters[i + 1..] {
+                layout.connect(&cluster[0], &other[0]);
+            }
+        }
+        layout
+    }
+
+    /// a breadth first spanning tree of this topology, rooted at `root`.
+    ///
+    /// Keeps every node, but only the links it was first reached over, ie to cut a grid down to
+    /// one path between each pair of nodes.  (nodes `root` can't reach are left unconnected)
+    pub fn spanning_tree(&self, root: &str) -> Self {
+        let mut tree = Self { neighbors: self.neighbors.keys().map(|id| (id.clone(), BTreeSet::new())).collect() };
+        if !self.neighbors.contains_key(root) { return tree; }
+
+        let mut seen = BTreeSet::from([root]);
+        let mut queue = VecDeque::from([root]);
+        while let Some(node) = queue.pop_front() {
+            for neighbor in &self.neighbors[node] {
+                if seen.insert(neighbor) {
+                    tree.connect(node, neighbor);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        tree
+    }
+
+    /// links `a` and `b`, in both directions.
+    pub fn connect(&mut self, a: &str, b: &str) {
+        if a == b { return; }
+        self.neighbors.entry(a.to_string()).or_default().insert(b.to_string());
+        self.neighbors.entry(b.to_string()).or_default().insert(a.to_string());
+    }
+
+    /// `node`'s neighbors, in sorted order.  (empty for an unknown node)
+    pub fn neighbors(&self, node: &str) -> Vec<NodeId> {
+        self.neighbors.get(node).map(|neighbors| neighbors.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// every node's neighbors, in the same shape as Maelstrom's `topology` message.
+    pub fn to_map(&self) -> HashMap<NodeId, Vec<NodeId>> {
+        self.neighbors.keys().map(|node| (node.clone(), self.neighbors(node))).collect()
+    }
+
+    /// the most neighbors any one node has.
+    pub fn fanout(&self) -> usize {
+        self.neighbors.values().map(|neighbors| neighbors.len()).max().unwrap_or(0)
+    }
+
+    /// the number of links, ie roughly the messages a broadcast flooded over every link costs.
+    pub fn edge_count(&self) -> usize {
+        self.neighbors.values().map(|neighbors| neighbors.len()).sum::<usize>() / 2
+    }
+
+    /// the most hops between any two nodes, or `None` if some node can't reach another.
+    pub fn diameter(&self) -> Option<usize> {
+        let mut diameter = 0;
+        for start in self.neighbors.keys() {
+            let mut hops = HashMap::from([(start, 0)]);
+            let mut queue = VecDeque::from([start]);
+            while let Some(node) = queue.pop_front() {
+                let next = hops[node] + 1;
+                for neighbor in &self.neighbors[node] {
+                    if !hops.contains_key(neighbor) {
+                        hops.insert(neighbor, next);
+                        queue.push_back(neighbor);
+                    }
+                }
+            }
+
+            if hops.len() < self.neighbors.len() { return None; }
+            diameter = diameter.max(hops.into_values().max().unwrap_or(0));
+        }
+        Some(diameter)
+    }
+}
+
+
+#[cfg(test)]
+mod topology_tests {
+    use super::*;
+
+    fn node_ids(n: usize) -> Vec<NodeId> {
+        (0..n).map(|i| format!("n{}", i)).collect()
+    }
+
+    #[test]
+    fn trees_and_stars() {
+        let tree = Topology::tree(&node_ids(7), 2);
+        assert_eq!(tree.neighbors("n0"), vec!["n1", "n2"]);
+        assert_eq!(tree.neighbors("n1"), vec!["n0", "n3", "n4"]);
+        assert_eq!((tree.edge_count(), tree.fanout(), tree.diameter()), (6, 3, Some(4)));
+
+        let star = Topology::star(&node_ids(25), "n3");
+        assert_eq!((star.edge_count(), star.fanout(), star.diameter()), (24, 24, Some(2)));
+        assert_eq!(star.neighbors("n7"), vec!["n3"]);
+
+        assert_eq!(Topology::star(&node_ids(3), "n9").diameter(), None);
+    }
+
+    #[test]
+    fn expanders_are_connected_with_bounded_fanout() {
+        let expander = Topology::expander(&node_ids(25), 4, 7);
+        assert_eq!(expander, Topology::expander(&node_ids(25), 4, 7), "the same seed should build the same layout");
+        assert!(expander.fanout() <= 4);
+        assert!(expander.diameter().is_some_and(|diameter| diameter <= 5), "diameter was {:?}", expander.diameter());
+
+        assert_eq!(Topology::expander(&node_ids(1), 4, 7).diameter(), Some(0));
+    }
+
+    #[test]
+    fn odd_expander_degrees_are_a_cap() {
+        for seed in 0..10 {
+            let expander = Topology::expander(&node_ids(25), 3, seed);
+            assert!(expander.fanout() <= 3, "seed {} gave a fanout of {}", seed, expander.fanout());
+            assert!(expander.diameter().is_some());
+        }
+        assert_eq!(Topology::expander(&node_ids(4), 1, 7).edge_count(), 2);
+    }
+
+    #[test]
+    fn hierarchical_clusters_are_three_hops_apart() {
+        let layout = Topology::hierarchical(&node_ids(25), 5);
+        assert_eq!(layout.neighbors("n5"), vec!["n0", "n10", "n15", "n20", "n6", "n7", "n8", "n9"]);
+        assert_eq!(layout.neighbors("n6"), vec!["n5", "n7", "n8", "n9"]);
+        assert_eq!((layout.fanout(), layout.diameter()), (8, Some(3)));
+    }
+
+    #[test]
+    fn spanning_tree_of_a_grid() {
+        // a 3x3 grid
+        let mut grid = Topology::unconnected(&node_ids(9));
+        for i in 0..9 {
+            if i % 3 < 2 { grid.connect(&format!("n{}", i), &format!("n{}", i + 1)); }
+            if i < 6 { grid.connect(&format!("n{}", i), &format!("n{}", i + 3)); }
+        }
+        assert_eq!((grid.edge_count(), grid.diameter()), (12, Some(4)));
+
+        let tree = grid.spanning_tree("n4");
+        assert_eq!(tree.edge_count(), 8);
+        assert_eq!(tree.neighbors("n4"), vec!["n1", "n3", "n5", "n7"]);
+        assert!(tree.diameter().is_some());
+
+        assert_eq!(Topology::from_map(&grid.to_map()), grid);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{future::Future, io};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpStream, ToSocketAddrs}, sync::mpsc};
+
+use crate::{data_models::*, io::{LineSink, LineSource}};
+
+
+/// An incoming message, as read from a `Transport`.
+pub enum Input {
+    Msg(NodeMessage),
+    /// a line that isn't a valid message.  `raw` is the line's json, if it was json at all.
+    Malformed { raw: Option<serde_json::Value>, error: String },
+}
+
+/// Where a `NodeRunner` reads its messages from, and writes its messages to.
+///
+/// `StdioTransport` is what Maelstrom expects, but the same node can be run over a socket
+/// (`StreamTransport`) or driven directly from a test (`ChannelTransport`).
+pub trait Transport: Send + 'static {
+    /// the next incoming message, or `None` once the input has been closed.
+    fn recv(&mut self) -> impl Future<Output = Option<Input>> + Send;
+
+    /// a handle for queueing outgoing messages.
+    fn sender(&self) -> mpsc::UnboundedSender<NodeMessage>;
+
+    /// called once the runner is done, after which anything queued must have been written out.
+    fn shutdown(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+
+/// Newline delimited JSON over stdin / stdout.
+pub struct StdioTransport {
+    source: LineSource,
+    sink: LineSink,
+}
+
+impl StdioTransport {
+    pub fn new() -> Self {
+        Self { source: LineSource::stdin(), sink: LineSink::stdout() }
+    }
+}
+
+impl Default for StdioTransport {
+    fn default() -> Self { Self::new() }
+}
+
+impl Transport for StdioTransport {
+    async fn recv(&mut self) -> Option<Input> {
+        self.source.next_msg().await
+    }
+
+    fn sender(&self) -> mpsc::UnboundedSender<NodeMessage> {
+        self.sink.sender()
+    }
+
+    async fn shutdown(&mut self) {
+        self.sink.shutdown().await
+    }
+}
+
+
+/// Newline delimited JSON over any reader / writer pair, ie a TCP or Unix socket.
+///
+/// This lets a node binary run outside of Maelstrom, connected to whatever routes
+/// messages between the nodes of a local cluster.
+pub struct StreamTransport {
+    source: LineSource,
+    sink: LineSink,
+}
+
+impl StreamTransport {
+    pub fn new<R, W>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        Self { source: LineSource::with_reader(reader), sink: LineSink::with_writer(writer) }
+    }
+
+    /// connects to `addr` over TCP.
+    pub async fn tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        Ok(Self::new(reader, writer))
+    }
+
+    /// connects to the Unix socket at `path`.
+    #[cfg(unix)]
+    pub async fn unix(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        let (reader, writer) = tokio::net::UnixStream::connect(path).await?.into_split();
+        Ok(Self::new(reader, writer))
+    }
+}
+
+impl Transport for StreamTransport {
+    async fn recv(&mut self) -> Option<Input> {
+        self.source.next_msg().await
+    }
+
+    fn sender(&self) -> mpsc::UnboundedSender<NodeMessage> {
+        self.sink.sender()
+    }
+
+    async fn shutdown(&mut self) {
+        self.sink.shutdown().await
+    }
+}
+
+
+/// Passes messages over in-memory channels, for driving a node from a test.
+///
+/// Messages are fed in, and the node's output read back, through the `ChannelHandle`
+/// returned alongside the transport.
+pub struct ChannelTransport {
+    input_rx: mpsc::UnboundedReceiver<NodeMessage>,
+    output_tx: mpsc::UnboundedSender<NodeMessage>,
+}
+
+/// The other end of a `ChannelTransport`.
+pub struct ChannelHandle {
+    input_tx: Option<mpsc::UnboundedSender<NodeMessage>>,
+    output_rx: mpsc::UnboundedReceiver<NodeMessage>,
+}
+
+impl ChannelTransport {
+    pub fn new() -> (Self, ChannelHandle) {
+        let (input_tx, input_rx) = mpsc::unbounded_channel();
+        let (output_tx, output_rx) = mpsc::unbounded_channel();
+
+        (Self { input_rx, output_tx }, ChannelHandle { input_tx: Some(input_tx), output_rx })
+    }
+}
+
+impl Transport for ChannelTransport {
+    async fn recv(&mut self) -> Option<Input> {
+        self.input_rx.recv().await.map(Input::Msg)
+    }
+
+    fn sender(&self) -> mpsc::UnboundedSender<NodeMessage> {
+        self.output_tx.clone()
+    }
+}
+
+impl ChannelHandle {
+    /// delivers `msg` to the node.  (dropped if the input has been closed)
+    pub fn send(&self, msg: NodeMessage) {
+        if let Some(tx) = &self.input_tx {
+            let _ = tx.send(msg);
+        }
+    }
+
+    /// the next message the node sent, or `None` once the node has been dropped.
+    pub async fn recv(&mut self) -> Option<NodeMessage> {
+        self.output_rx.recv().await
+    }
+
+    /// closes the node's input, the same as stdin closing.
+    pub fn close(&mut self) {
+        self.input_tx = None;
+    }
+}